@@ -0,0 +1,154 @@
+// Compare sending a large file by reading it into memory against the
+// server's own `copy_range`, with sendfile(2) and with its buffered fallback.
+//
+//     cargo bench --bench sendfile
+//
+// `MINGINX_BENCH_SIZE` sets the size of the generated file (default 2 GiB),
+// `MINGINX_BENCH_FILE` benchmarks an existing file instead. Each strategy
+// runs in its own process so the peak resident set size is its own.
+
+// The module the server sends files with. Its tests are not run from here.
+#[cfg(target_os = "linux")]
+#[path = "../src/server/sendfile.rs"]
+#[allow(unused_imports)]
+mod sendfile;
+
+#[cfg(target_os = "linux")]
+mod bench {
+    use std::{
+        env,
+        fs::{self, File},
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        path::{Path, PathBuf},
+        process::Command,
+        thread,
+        time::Instant,
+    };
+
+    use super::sendfile::copy_range;
+
+    const STRATEGIES: [&str; 3] = ["read", "sendfile", "buffered"];
+
+    pub fn main() {
+        let args: Vec<String> = env::args().collect();
+        if let Some(i) = args.iter().position(|arg| arg == "--strategy") {
+            let strategy = &args[i + 1];
+            let path = PathBuf::from(&args[i + 2]);
+            return child(strategy, &path);
+        }
+
+        let (path, generated) = match env::var_os("MINGINX_BENCH_FILE") {
+            Some(path) => (PathBuf::from(path), false),
+            None => {
+                let size = env::var("MINGINX_BENCH_SIZE")
+                    .ok()
+                    .and_then(|size| size.parse().ok())
+                    .unwrap_or(2u64 << 30);
+                (generate(size), true)
+            }
+        };
+        let size = fs::metadata(&path).unwrap().len();
+        println!("file: {} ({} MiB)", path.display(), size >> 20);
+        println!("{:<10} {:>12} {:>14}", "strategy", "MiB/s", "peak RSS MiB");
+
+        let exe = env::current_exe().unwrap();
+        for strategy in STRATEGIES {
+            let output = Command::new(&exe)
+                .arg("--strategy")
+                .arg(strategy)
+                .arg(&path)
+                .output()
+                .unwrap();
+            print!("{}", String::from_utf8_lossy(&output.stdout));
+            if !output.status.success() {
+                eprint!("{}", String::from_utf8_lossy(&output.stderr));
+            }
+        }
+
+        if generated {
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    // A file with non-zero pages so neither strategy reads holes
+    fn generate(size: u64) -> PathBuf {
+        let path = env::temp_dir().join(format!("minginx-bench-{}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        let block: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let mut written = 0;
+        while written < size {
+            let len = (size - written).min(block.len() as u64) as usize;
+            file.write_all(&block[..len]).unwrap();
+            written += len as u64;
+        }
+        path
+    }
+
+    fn child(strategy: &str, path: &Path) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let reader = thread::spawn(move || {
+            let mut buf = vec![0; 1 << 16];
+            let mut total = 0u64;
+            loop {
+                match client.read(&mut buf).unwrap() {
+                    0 => return total,
+                    read => total += read as u64,
+                }
+            }
+        });
+
+        let start = Instant::now();
+        if strategy == "read" {
+            let content = fs::read(path).unwrap();
+            server.write_all(&content).unwrap();
+            drop(server);
+        } else {
+            let file = File::open(path).unwrap();
+            let len = file.metadata().unwrap().len();
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                server.set_nonblocking(true).unwrap();
+                // Dropped at the end so the reader sees the connection close
+                let mut server = tokio::net::TcpStream::from_std(server).unwrap();
+                copy_range(&mut server, &file, 0..len, strategy == "sendfile")
+                    .await
+                    .unwrap();
+            });
+        }
+        let total = reader.join().unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+
+        assert_eq!(fs::metadata(path).unwrap().len(), total);
+        println!(
+            "{:<10} {:>12.0} {:>14}",
+            strategy,
+            total as f64 / elapsed / (1 << 20) as f64,
+            peak_rss_kib() >> 10
+        );
+    }
+
+    fn peak_rss_kib() -> u64 {
+        fs::read_to_string("/proc/self/status")
+            .unwrap_or_default()
+            .lines()
+            .find_map(|line| line.strip_prefix("VmHWM:"))
+            .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() {
+    bench::main();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    println!("the sendfile benchmark only runs on Linux");
+}
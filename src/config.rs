@@ -0,0 +1,610 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    glob::glob_match,
+    http::{
+        conditional::EtagMode,
+        encoding::{self, Encoding},
+        mime::{self, MimeTypes},
+        uri::percent_decode,
+    },
+    server::{
+        archive::ArchiveConfig, autoindex::Format, balancer::UpstreamConfig,
+        cache::FileCacheConfig, cgi::CgiConfig, fastcgi::FastCgiConfig, gateway::GatewayConfig,
+        proxy::ProxyConfig,
+    },
+};
+
+// Server configuration, read from a TOML file passed with `--config`.
+// Command line flags take precedence over the values in the file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub port: u16,
+    pub php: bool,
+    // PHP CGI binary run for `.php` scripts
+    pub php_cgi: PathBuf,
+    // Script run for requests matching no file or directory, e.g.
+    // `/index.php` for frameworks routing on `REQUEST_URI`
+    pub php_front_controller: Option<String>,
+    pub fastcgi: FastCgiConfig,
+    pub cgi: CgiConfig,
+    // SCGI and uwsgi applications mounted at a path prefix
+    #[serde(rename = "gateway")]
+    pub gateways: Vec<GatewayConfig>,
+    // HTTP servers requests are forwarded to, by path prefix
+    #[serde(rename = "proxy")]
+    pub proxies: Vec<ProxyConfig>,
+    // Server groups a proxy can `pass` to by name
+    #[serde(rename = "upstream")]
+    pub upstreams: Vec<UpstreamConfig>,
+    // Largest request body accepted, in bytes
+    pub client_max_body_size: u64,
+    // Document root used when no location matches
+    pub root: PathBuf,
+    // Files tried in order when a directory is requested
+    pub index: Vec<String>,
+    // nginx-style `mime.types` file merged over the built-in table
+    pub mime_types: Option<PathBuf>,
+    // Content type for files with an unknown extension
+    pub default_type: String,
+    // Guess the type of unknown files from their content instead of
+    // sending `default_type` with `X-Content-Type-Options: nosniff`
+    pub sniff: bool,
+    // `off`, `strong`, `weak` or `content`
+    pub etag: EtagMode,
+    // Generate listings for directories without an index file
+    pub autoindex: bool,
+    // `html`, `json` or `xml`; clients can ask for another with `Accept`
+    pub autoindex_format: Format,
+    #[serde(rename = "location")]
+    pub locations: Vec<Location>,
+    pub compression: Compression,
+    pub access: Access,
+    pub archive: ArchiveConfig,
+    pub file_cache: FileCacheConfig,
+    // Files of at least this many bytes are streamed from disk
+    // instead of being read into memory or cached
+    pub stream_min_size: u64,
+    // Copy streamed files with sendfile(2) where available
+    pub sendfile: bool,
+    // Pages sent with error responses, by status code
+    #[serde(rename = "error_page")]
+    pub error_pages: Vec<ErrorPage>,
+    // Caching policies, the first matching rule applies
+    #[serde(rename = "cache")]
+    pub cache_rules: Vec<CacheRule>,
+    #[serde(skip)]
+    pub mime: MimeTypes,
+}
+
+// A path prefix served from its own directory.
+// `root` appends the full request path, `alias` replaces the prefix.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Location {
+    pub prefix: String,
+    pub root: Option<PathBuf>,
+    pub alias: Option<PathBuf>,
+    // Overrides the global `autoindex` below this prefix
+    pub autoindex: Option<bool>,
+}
+
+// Body for error responses with one of `codes`: a file on disk, or a request
+// path resolved like any other (`root`, locations and access rules apply)
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ErrorPage {
+    pub codes: Vec<u16>,
+    pub file: Option<PathBuf>,
+    pub uri: Option<String>,
+}
+
+// Files that are neither served nor listed
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Access {
+    // Allow names starting with `.`, such as `.git` or `.env`
+    pub dotfiles: bool,
+    // Glob patterns; those without a `/` are matched against every path segment
+    pub deny: Vec<String>,
+    // Status sent for denied paths and disabled listings, 403 or 404
+    pub deny_status: u16,
+}
+
+impl Default for Access {
+    fn default() -> Self {
+        Self {
+            dotfiles: false,
+            deny: Vec::new(),
+            deny_status: 404,
+        }
+    }
+}
+
+impl Access {
+    // Whether the decoded request path `uri_path` names or lies inside a denied file
+    pub fn denied(&self, uri_path: &str) -> bool {
+        let segments: Vec<&str> = uri_path.split('/').filter(|s| !s.is_empty()).collect();
+        if !self.dotfiles && segments.iter().any(|segment| segment.starts_with('.')) {
+            return true;
+        }
+        self.deny.iter().any(|pattern| {
+            if !pattern.contains('/') {
+                return segments.iter().any(|segment| glob_match(pattern, segment));
+            }
+            // `/private` also covers everything below it
+            let mut prefix = String::new();
+            segments.iter().any(|segment| {
+                prefix.push('/');
+                prefix.push_str(segment);
+                glob_match(pattern, &prefix) || glob_match(pattern, &format!("{prefix}/"))
+            })
+        })
+    }
+}
+
+// On-the-fly response compression
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Compression {
+    pub enabled: bool,
+    // Smaller bodies are sent as is
+    pub min_length: usize,
+    // 0-9, scaled for brotli
+    pub level: u32,
+    // Codings offered, in order of preference
+    pub encodings: Vec<Encoding>,
+    // Extra MIME types to compress besides the built-in text types
+    pub types: Vec<String>,
+    // Serve `file.br`/`file.gz` next to a file instead of compressing it,
+    // even when on-the-fly compression is disabled
+    pub precompressed: bool,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_length: 1024,
+            level: 6,
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            types: Vec::new(),
+            precompressed: true,
+        }
+    }
+}
+
+impl Compression {
+    pub fn compressible(&self, content_type: &str) -> bool {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        encoding::is_compressible(mime) || self.types.iter().any(|t| t == mime)
+    }
+}
+
+// Caching headers for responses matching every given criterion.
+// `glob` is matched against the request path, or only the file name when it has no `/`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct CacheRule {
+    pub prefix: Option<String>,
+    pub glob: Option<String>,
+    pub extensions: Vec<String>,
+    pub cache_control: Option<String>,
+    // Seconds from now for `Expires`, derived from `max-age` when unset
+    pub expires: Option<i64>,
+    pub vary: Vec<String>,
+}
+
+impl CacheRule {
+    pub fn matches(&self, uri_path: &str, path: &Path) -> bool {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+
+        self.prefix
+            .as_ref()
+            .is_none_or(|prefix| under_prefix(uri_path, prefix))
+            && self.glob.as_ref().is_none_or(|glob| {
+                if glob.contains('/') {
+                    glob_match(glob, uri_path)
+                } else {
+                    glob_match(glob, &file_name)
+                }
+            })
+            && (self.extensions.is_empty()
+                || extension.is_some_and(|ext| {
+                    self.extensions
+                        .iter()
+                        .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(&ext))
+                }))
+    }
+
+    // Lifetime in seconds used for the `Expires` header
+    pub fn expires(&self) -> Option<i64> {
+        self.expires.or_else(|| {
+            let cache_control = self.cache_control.as_ref()?;
+            cache_control
+                .split(',')
+                .find_map(|directive| directive.trim().strip_prefix("max-age="))
+                .and_then(|max_age| max_age.trim().parse().ok())
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: 5000,
+            php: false,
+            php_cgi: PathBuf::from("php-cgi"),
+            php_front_controller: None,
+            fastcgi: FastCgiConfig::default(),
+            cgi: CgiConfig::default(),
+            gateways: Vec::new(),
+            proxies: Vec::new(),
+            upstreams: Vec::new(),
+            client_max_body_size: 1024 * 1024,
+            root: PathBuf::from("."),
+            index: vec!["index.html".into(), "index.htm".into(), "index.php".into()],
+            mime_types: None,
+            default_type: "application/octet-stream".into(),
+            sniff: false,
+            etag: EtagMode::Strong,
+            autoindex: true,
+            autoindex_format: Format::Html,
+            locations: Vec::new(),
+            compression: Compression::default(),
+            access: Access::default(),
+            archive: ArchiveConfig::default(),
+            file_cache: FileCacheConfig::default(),
+            stream_min_size: 1024 * 1024,
+            sendfile: true,
+            error_pages: vec![ErrorPage {
+                codes: vec![404],
+                file: Some(PathBuf::from("./static/404.html")),
+                uri: None,
+            }],
+            cache_rules: Vec::new(),
+            mime: MimeTypes::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read_to_string(path)?;
+        let mut config: Self =
+            toml::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(mime_types) = &config.mime_types {
+            config.mime.load(mime_types)?;
+        }
+        Ok(config)
+    }
+
+    // MIME type of `path` by its extension alone
+    pub fn mime_type(&self, path: &Path) -> &str {
+        self.mime.lookup(path).unwrap_or(&self.default_type)
+    }
+
+    // `Content-Type` header value for a file served from `path`.
+    // `content` is only inspected when the extension is unknown.
+    pub fn content_type(&self, path: &Path, content: &[u8]) -> String {
+        let mime = self
+            .mime
+            .lookup(path)
+            .or_else(|| self.sniff.then(|| mime::sniff(content)).flatten())
+            .unwrap_or(&self.default_type);
+        mime::with_charset(mime)
+    }
+
+    // The caching policy for a response to `uri_path` served from `path`
+    pub fn cache_rule(&self, uri_path: &str, path: &Path) -> Option<&CacheRule> {
+        self.cache_rules
+            .iter()
+            .find(|rule| rule.matches(uri_path, path))
+    }
+
+    // The location with the longest prefix matching `uri_path`
+    pub fn location(&self, uri_path: &str) -> Option<&Location> {
+        self.locations
+            .iter()
+            .filter(|location| under_prefix(uri_path, &location.prefix))
+            .max_by_key(|location| location.prefix.len())
+    }
+
+    // The gateway with the longest prefix matching `uri_path`
+    pub fn gateway(&self, uri_path: &str) -> Option<&GatewayConfig> {
+        self.gateways
+            .iter()
+            .filter(|gateway| under_prefix(uri_path, &gateway.prefix))
+            .max_by_key(|gateway| gateway.prefix.len())
+    }
+
+    // The proxy with the longest prefix matching `uri_path`
+    pub fn proxy(&self, uri_path: &str) -> Option<&ProxyConfig> {
+        self.proxies
+            .iter()
+            .filter(|proxy| under_prefix(uri_path, &proxy.prefix))
+            .max_by_key(|proxy| proxy.prefix.len())
+    }
+
+    // Directory `uri_path` is served from: the location's `alias` or `root`,
+    // or the global root
+    pub fn document_root(&self, uri_path: &str) -> &Path {
+        match self.location(&percent_decode(uri_path)) {
+            Some(Location {
+                alias: Some(dir), ..
+            })
+            | Some(Location {
+                root: Some(dir), ..
+            }) => dir,
+            _ => &self.root,
+        }
+    }
+
+    // File holding the error page for `code`, if one is configured
+    pub fn error_page(&self, code: u16) -> Option<PathBuf> {
+        let page = self
+            .error_pages
+            .iter()
+            .find(|page| page.codes.contains(&code))?;
+        match (&page.file, &page.uri) {
+            (Some(file), _) => Some(file.clone()),
+            (None, Some(uri)) if !self.access.denied(&percent_decode(uri)) => self.resolve(uri),
+            _ => None,
+        }
+    }
+
+    // Whether directories below `uri_path` may be listed
+    pub fn autoindex(&self, uri_path: &str) -> bool {
+        self.location(uri_path)
+            .and_then(|location| location.autoindex)
+            .unwrap_or(self.autoindex)
+    }
+
+    // Map a request path onto the filesystem.
+    // Returns `None` for paths trying to escape the document root.
+    pub fn resolve(&self, uri_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(uri_path);
+        let (base, rest) = match self.location(&decoded) {
+            Some(Location {
+                alias: Some(alias),
+                prefix,
+                ..
+            }) => (alias, &decoded[prefix.trim_end_matches('/').len()..]),
+            Some(Location {
+                root: Some(root), ..
+            }) => (root, &decoded[..]),
+            _ => (&self.root, &decoded[..]),
+        };
+
+        let mut path = base.clone();
+        for segment in rest.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return None,
+                segment if segment.contains('\\') || segment.contains('\0') => return None,
+                segment => path.push(segment),
+            }
+        }
+        Some(path)
+    }
+}
+
+// Whether `uri_path` is `prefix` or below it, matching whole path segments
+// so that `/app` does not cover `/application`
+fn under_prefix(uri_path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    uri_path
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::server::gateway::Protocol;
+
+    use super::{Access, CacheRule, Config, ErrorPage, Location};
+
+    fn config() -> Config {
+        Config {
+            root: PathBuf::from("/srv/www"),
+            locations: vec![
+                Location {
+                    prefix: "/docs/".into(),
+                    root: Some(PathBuf::from("/srv/docs")),
+                    alias: None,
+                    autoindex: Some(false),
+                },
+                Location {
+                    prefix: "/assets/".into(),
+                    root: None,
+                    alias: Some(PathBuf::from("/srv/build/static")),
+                    autoindex: None,
+                },
+            ],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let config = config();
+        assert_eq!(
+            Some(PathBuf::from("/srv/www/a b/index.html")),
+            config.resolve("/a%20b/index.html")
+        );
+        assert_eq!(
+            Some(PathBuf::from("/srv/docs/docs/guide.html")),
+            config.resolve("/docs/guide.html")
+        );
+        assert_eq!(
+            Some(PathBuf::from("/srv/build/static/app.js")),
+            config.resolve("/assets/app.js")
+        );
+        // Prefixes end at a path segment
+        assert_eq!(
+            Some(PathBuf::from("/srv/build/static")),
+            config.resolve("/assets")
+        );
+        assert_eq!(
+            Some(PathBuf::from("/srv/www/assetsx/a.js")),
+            config.resolve("/assetsx/a.js")
+        );
+        assert_eq!(PathBuf::from("/srv/www"), config.document_root("/docsx/a"));
+    }
+
+    #[test]
+    fn test_resolve_traversal() {
+        let config = config();
+        assert_eq!(None, config.resolve("/../etc/passwd"));
+        assert_eq!(None, config.resolve("/assets/%2e%2e/secret"));
+    }
+
+    #[test]
+    fn test_access() {
+        let access = Access {
+            deny: vec!["*~".into(), "*.{bak,swp}".into(), "/private".into()],
+            ..Access::default()
+        };
+        assert!(access.denied("/.env"));
+        assert!(access.denied("/.git/config"));
+        assert!(access.denied("/a/index.html~"));
+        assert!(access.denied("/a/b.swp"));
+        assert!(access.denied("/private"));
+        assert!(access.denied("/private/"));
+        assert!(access.denied("/private/keys/a.pem"));
+        assert!(!access.denied("/privately.html"));
+        assert!(!access.denied("/a/index.html"));
+        assert!(!access.denied("/"));
+
+        let access = Access {
+            dotfiles: true,
+            ..Access::default()
+        };
+        assert!(!access.denied("/.well-known/security.txt"));
+
+        let config = config();
+        assert!(!config.autoindex("/docs/guide/"));
+        assert!(config.autoindex("/assets/"));
+    }
+
+    #[test]
+    fn test_error_page() {
+        let config = Config {
+            error_pages: vec![
+                ErrorPage {
+                    codes: vec![500, 502, 503],
+                    uri: Some("/docs/50x.html".into()),
+                    ..ErrorPage::default()
+                },
+                ErrorPage {
+                    codes: vec![403],
+                    uri: Some("/.errors/403.html".into()),
+                    ..ErrorPage::default()
+                },
+            ],
+            ..config()
+        };
+        assert_eq!(
+            Some(PathBuf::from("/srv/docs/docs/50x.html")),
+            config.error_page(502)
+        );
+        assert_eq!(None, config.error_page(403));
+        assert_eq!(None, config.error_page(404));
+        assert_eq!(
+            Some(PathBuf::from("./static/404.html")),
+            Config::default().error_page(404)
+        );
+    }
+
+    #[test]
+    fn test_cache_rule() {
+        let config = Config {
+            cache_rules: vec![
+                CacheRule {
+                    prefix: Some("/assets/".into()),
+                    glob: Some("*.[0-9a-f][0-9a-f][0-9a-f][0-9a-f]*.*".into()),
+                    cache_control: Some("public, max-age=31536000, immutable".into()),
+                    ..CacheRule::default()
+                },
+                CacheRule {
+                    extensions: vec!["html".into(), ".htm".into()],
+                    cache_control: Some("no-cache".into()),
+                    ..CacheRule::default()
+                },
+            ],
+            ..Config::default()
+        };
+
+        let hashed = config
+            .cache_rule(
+                "/assets/app.3fa91c.js",
+                &PathBuf::from("./assets/app.3fa91c.js"),
+            )
+            .unwrap();
+        assert_eq!(Some(31536000), hashed.expires());
+        assert!(config
+            .cache_rule("/assets/app.js", &PathBuf::from("./assets/app.js"))
+            .is_none());
+
+        let html = config
+            .cache_rule("/docs/", &PathBuf::from("./docs/INDEX.HTML"))
+            .unwrap();
+        assert_eq!(Some("no-cache"), html.cache_control.as_deref());
+        assert_eq!(None, html.expires());
+    }
+
+    #[test]
+    fn test_gateway() {
+        let config: Config = toml::from_str(
+            r#"
+            [[gateway]]
+            prefix = "/app/"
+            protocol = "uwsgi"
+            address = "unix:/run/uwsgi/app.sock"
+
+            [[gateway]]
+            prefix = "/app/api/"
+            protocol = "scgi"
+            address = "127.0.0.1:4000"
+            params = { HTTPS = "on" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            Protocol::Uwsgi,
+            config.gateway("/app/users").unwrap().protocol
+        );
+        let api = config.gateway("/app/api/users").unwrap();
+        assert_eq!("127.0.0.1:4000", api.address);
+        assert_eq!(60, api.timeout);
+        assert_eq!("on", api.params["HTTPS"]);
+        assert!(config.gateway("/static/app.js").is_none());
+        assert!(config.gateway("/application").is_none());
+        assert!(config.gateway("/app").is_some());
+
+        let config: Config = toml::from_str(
+            r#"
+            [[gateway]]
+            prefix = "/app"
+            protocol = "scgi"
+            address = "127.0.0.1:4000"
+            "#,
+        )
+        .unwrap();
+        assert!(config.gateway("/app/users").is_some());
+        assert!(config.gateway("/application").is_none());
+    }
+}
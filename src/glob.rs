@@ -0,0 +1,97 @@
+// Shell-style pattern matching used by config rules.
+//
+// `*` matches within one path segment, `**` across segments, `?` one
+// character, `[a-z]`/`[!a-z]` a class and `{js,css}` any alternative.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    expand_braces(pattern).iter().any(|pattern| {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let text: Vec<char> = text.chars().collect();
+        match_chars(&pattern, &text)
+    })
+}
+
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let open = match pattern.find('{') {
+        Some(open) => open,
+        None => return vec![pattern.to_string()],
+    };
+    let close = match pattern[open..].find('}') {
+        Some(close) => open + close,
+        None => return vec![pattern.to_string()],
+    };
+    let (head, tail) = (&pattern[..open], &pattern[close + 1..]);
+    pattern[open + 1..close]
+        .split(',')
+        .flat_map(|alt| expand_braces(&format!("{head}{alt}{tail}")))
+        .collect()
+}
+
+fn match_chars(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            // `**/` also matches zero directories
+            let rest_no_slash = rest.strip_prefix(&['/'][..]).unwrap_or(rest);
+            (0..=text.len())
+                .any(|i| match_chars(rest, &text[i..]) || match_chars(rest_no_slash, &text[i..]))
+        }
+        Some('*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if match_chars(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some('?') => {
+            matches!(text.first(), Some(c) if *c != '/') && match_chars(&pattern[1..], &text[1..])
+        }
+        Some('[') => match (text.first(), pattern.iter().position(|c| *c == ']')) {
+            (Some(c), Some(end)) if end > 1 => {
+                let class = &pattern[1..end];
+                let (negate, class) = match class.first() {
+                    Some('!') | Some('^') => (true, &class[1..]),
+                    _ => (false, class),
+                };
+                let mut found = false;
+                let mut i = 0;
+                while i < class.len() {
+                    if i + 2 < class.len() && class[i + 1] == '-' {
+                        found |= (class[i]..=class[i + 2]).contains(c);
+                        i += 3;
+                    } else {
+                        found |= class[i] == *c;
+                        i += 1;
+                    }
+                }
+                found != negate && match_chars(&pattern[end + 1..], &text[1..])
+            }
+            (Some(c), _) => *c == '[' && match_chars(&pattern[1..], &text[1..]),
+            (None, _) => false,
+        },
+        Some(p) => text.first() == Some(p) && match_chars(&pattern[1..], &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::glob_match;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.html", "index.html"));
+        assert!(!glob_match("*.html", "a/index.html"));
+        assert!(glob_match("/assets/**", "/assets/js/app.js"));
+        assert!(glob_match("/assets/**/*.js", "/assets/app.js"));
+        assert!(glob_match("*.{js,css}", "app.css"));
+        assert!(glob_match("app.[0-9a-f]*.js", "app.3fa9.js"));
+        assert!(!glob_match("app.[!0-9]*.js", "app.3fa9.js"));
+        assert!(glob_match("?.txt", "a.txt"));
+        assert!(!glob_match("?.txt", "ab.txt"));
+    }
+}
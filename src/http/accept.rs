@@ -0,0 +1,90 @@
+// Parse an `Accept` or `Accept-Encoding` style header into lowercased
+// (value, q-value) pairs
+pub fn parse_qvalues(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let value = params.next()?.trim().to_ascii_lowercase();
+            if value.is_empty() {
+                return None;
+            }
+            let q = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((value, q))
+        })
+        .collect()
+}
+
+// Pick the media type to respond with from `offered`, using the q-values of
+// the `Accept` header. A type only matched through `*/*` loses to one the
+// client names, and ties go to the earlier offer, so list the default first.
+pub fn negotiate<'a>(accept: Option<&str>, offered: &[&'a str]) -> Option<&'a str> {
+    let ranges = parse_qvalues(accept?);
+
+    // q-value of the most specific range matching `mime`, and how specific it is
+    let q_of = |mime: &str| {
+        let main_type = mime.split('/').next().unwrap_or_default();
+        let find = |range: &str| ranges.iter().find(|(r, _)| r == range).map(|(_, q)| *q);
+        find(mime)
+            .map(|q| (q, 2))
+            .or_else(|| find(&format!("{main_type}/*")).map(|q| (q, 1)))
+            .or_else(|| find("*/*").map(|q| (q, 0)))
+            .unwrap_or((0.0, 0))
+    };
+
+    let mut best = None;
+    let mut best_rank = (0.0, 0);
+    for mime in offered {
+        let rank = q_of(mime);
+        if rank.0 > 0.0 && (rank.0 > best_rank.0 || (rank.0 == best_rank.0 && rank.1 > best_rank.1))
+        {
+            best = Some(*mime);
+            best_rank = rank;
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod test {
+    use super::{negotiate, parse_qvalues};
+
+    const OFFERED: [&str; 3] = ["text/html", "application/json", "application/xml"];
+
+    #[test]
+    fn test_parse_qvalues() {
+        assert_eq!(
+            vec![("text/html".to_string(), 1.0), ("*/*".to_string(), 0.1)],
+            parse_qvalues("Text/HTML, , */*;level=1;q=0.1")
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(None, negotiate(None, &OFFERED));
+        assert_eq!(Some("text/html"), negotiate(Some("*/*"), &OFFERED));
+        assert_eq!(
+            Some("application/json"),
+            negotiate(Some("application/json"), &OFFERED)
+        );
+        assert_eq!(
+            Some("text/html"),
+            negotiate(
+                Some("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"),
+                &OFFERED
+            )
+        );
+        assert_eq!(
+            Some("application/xml"),
+            negotiate(Some("application/*;q=0.5, application/xml"), &OFFERED)
+        );
+        assert_eq!(
+            Some("application/json"),
+            negotiate(Some("application/json, */*"), &OFFERED)
+        );
+        assert_eq!(None, negotiate(Some("image/png"), &OFFERED));
+    }
+}
@@ -0,0 +1,151 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
+
+// Longest chunk-size line or trailer field accepted
+const MAX_LINE: usize = 8 * 1024;
+
+// Decodes a `Transfer-Encoding: chunked` body, ending at the last chunk.
+// Trailer fields are read and dropped.
+pub struct ChunkedReader<R> {
+    inner: R,
+    state: State,
+    line: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Size,
+    Data(u64),
+    // The CRLF after a chunk's data
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+impl<R: AsyncBufRead + Unpin> ChunkedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            state: State::Size,
+            line: Vec::new(),
+        }
+    }
+
+    // Read up to and including the next LF into `line`
+    fn poll_line(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            let available = ready!(Pin::new(&mut self.inner).poll_fill_buf(cx))?;
+            if available.is_empty() {
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            let (used, done) = match available.iter().position(|&b| b == b'\n') {
+                Some(end) => (end + 1, true),
+                None => (available.len(), false),
+            };
+            self.line.extend_from_slice(&available[..used]);
+            Pin::new(&mut self.inner).consume(used);
+            if self.line.len() > MAX_LINE {
+                return Poll::Ready(Err(invalid("chunk line too long")));
+            }
+            if done {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for ChunkedReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match this.state {
+                State::Size => {
+                    ready!(this.poll_line(cx))?;
+                    let line = String::from_utf8_lossy(&this.line);
+                    // Chunk extensions follow a `;`
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    let size =
+                        u64::from_str_radix(size, 16).map_err(|_| invalid("invalid chunk size"))?;
+                    this.line.clear();
+                    this.state = match size {
+                        0 => State::Trailer,
+                        size => State::Data(size),
+                    };
+                }
+                State::Data(remaining) => {
+                    let available = ready!(Pin::new(&mut this.inner).poll_fill_buf(cx))?;
+                    if available.is_empty() {
+                        return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                    }
+                    let len = available
+                        .len()
+                        .min(buf.remaining())
+                        .min(remaining.try_into().unwrap_or(usize::MAX));
+                    buf.put_slice(&available[..len]);
+                    Pin::new(&mut this.inner).consume(len);
+                    this.state = match remaining - len as u64 {
+                        0 => State::DataEnd,
+                        remaining => State::Data(remaining),
+                    };
+                    return Poll::Ready(Ok(()));
+                }
+                State::DataEnd => {
+                    ready!(this.poll_line(cx))?;
+                    if this.line.trim_ascii() != b"" {
+                        return Poll::Ready(Err(invalid("missing CRLF after chunk")));
+                    }
+                    this.line.clear();
+                    this.state = State::Size;
+                }
+                State::Trailer => {
+                    ready!(this.poll_line(cx))?;
+                    let end = this.line.trim_ascii().is_empty();
+                    this.line.clear();
+                    if end {
+                        this.state = State::Done;
+                    }
+                }
+                State::Done => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, BufReader};
+
+    use super::ChunkedReader;
+
+    async fn decode(body: &[u8]) -> std::io::Result<Vec<u8>> {
+        // A tiny buffer makes every state resume mid-line
+        let mut reader = ChunkedReader::new(BufReader::with_capacity(3, body));
+        let mut decoded = Vec::new();
+        reader.read_to_end(&mut decoded).await?;
+        Ok(decoded)
+    }
+
+    #[tokio::test]
+    async fn test_chunked_reader() {
+        let body = b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nExpires: 0\r\n\r\nnext";
+        assert_eq!(b"hello, world".to_vec(), decode(body).await.unwrap());
+        assert_eq!(b"".to_vec(), decode(b"0\r\n\r\n").await.unwrap());
+
+        assert!(decode(b"5\r\nhel").await.is_err());
+        assert!(decode(b"5\r\nhelloX\r\n0\r\n\r\n").await.is_err());
+        assert!(decode(b"zz\r\n").await.is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+
+use super::{date::parse_http_date, request::Request};
+
+// How static files get their `ETag`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EtagMode {
+    Off,
+    // Strong tag from size, modification time and inode
+    Strong,
+    // Same as `Strong` but marked weak (`W/"..."`)
+    Weak,
+    // Strong tag from a hash of the file content
+    Content,
+}
+
+// Validators describing the current representation of a resource
+#[derive(Debug, Clone, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<SystemTime>,
+}
+
+// Outcome of evaluating the conditional request headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+// Build an entity tag for a file, or `None` when ETags are disabled
+pub fn make_etag(
+    mode: EtagMode,
+    len: u64,
+    modified: Option<SystemTime>,
+    inode: Option<u64>,
+    content: &[u8],
+) -> Option<String> {
+    let mtime = modified
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let metadata_tag = match inode {
+        Some(inode) => format!("{:x}-{:x}-{:x}", inode, len, mtime.as_nanos()),
+        None => format!("{:x}-{:x}", len, mtime.as_nanos()),
+    };
+    match mode {
+        EtagMode::Off => None,
+        EtagMode::Strong => Some(format!("\"{metadata_tag}\"")),
+        EtagMode::Weak => Some(format!("W/\"{metadata_tag}\"")),
+        EtagMode::Content => Some(format!("\"{:016x}-{:x}\"", fnv1a(content), len)),
+    }
+}
+
+// 64-bit FNV-1a, stable across runs and builds unlike `DefaultHasher`
+fn fnv1a(content: &[u8]) -> u64 {
+    content.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Split an `If-Match`/`If-None-Match` list into entity tags (quotes included)
+fn parse_etags(value: &str) -> Vec<&str> {
+    let mut tags = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        let start = if rest.starts_with("W/\"") { 2 } else { 0 };
+        if !rest[start..].starts_with('"') {
+            break;
+        }
+        match rest[start + 1..].find('"') {
+            Some(end) => {
+                let end = start + 1 + end + 1;
+                tags.push(&rest[..end]);
+                rest = &rest[end..];
+            }
+            None => break,
+        }
+    }
+    tags
+}
+
+fn opaque(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+// Strong comparison: both tags strong and identical
+pub fn strong_match(a: &str, b: &str) -> bool {
+    !a.starts_with("W/") && !b.starts_with("W/") && a == b
+}
+
+// Weak comparison: opaque tags identical, weakness ignored
+pub fn weak_match(a: &str, b: &str) -> bool {
+    opaque(a) == opaque(b)
+}
+
+fn list_matches(header: &str, etag: Option<&str>, strong: bool) -> bool {
+    if header.trim() == "*" {
+        return true;
+    }
+    match etag {
+        Some(etag) => parse_etags(header).into_iter().any(|tag| {
+            if strong {
+                strong_match(tag, etag)
+            } else {
+                weak_match(tag, etag)
+            }
+        }),
+        None => false,
+    }
+}
+
+fn secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Evaluate `If-Match`, `If-Unmodified-Since`, `If-None-Match` and
+// `If-Modified-Since` in the order given by RFC 9110 section 13.2.2
+pub fn evaluate(req: &Request, validators: &Validators) -> Precondition {
+    let etag = validators.etag.as_deref();
+    let modified = validators.last_modified;
+
+    if let Some(if_match) = req.header("If-Match") {
+        if !list_matches(if_match, etag, true) {
+            return Precondition::Failed;
+        }
+    } else if let (Some(since), Some(modified)) = (
+        req.header("If-Unmodified-Since").and_then(parse_http_date),
+        modified,
+    ) {
+        if secs(modified) > secs(since) {
+            return Precondition::Failed;
+        }
+    }
+
+    if let Some(if_none_match) = req.header("If-None-Match") {
+        if list_matches(if_none_match, etag, false) {
+            return Precondition::NotModified;
+        }
+    } else if let (Some(since), Some(modified)) = (
+        req.header("If-Modified-Since").and_then(parse_http_date),
+        modified,
+    ) {
+        if secs(modified) <= secs(since) {
+            return Precondition::NotModified;
+        }
+    }
+
+    Precondition::Proceed
+}
+
+// Whether an `If-Range` validator still matches, so the `Range` header applies.
+// Entity tags use strong comparison; dates must equal the modification time.
+pub fn if_range_matches(if_range: &str, validators: &Validators) -> bool {
+    let if_range = if_range.trim();
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        return validators
+            .etag
+            .as_deref()
+            .is_some_and(|etag| strong_match(if_range, etag));
+    }
+    match (parse_http_date(if_range), validators.last_modified) {
+        (Some(date), Some(modified)) => secs(date) == secs(modified),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::http::request::Request;
+
+    use super::{
+        evaluate, if_range_matches, make_etag, parse_etags, weak_match, EtagMode, Precondition,
+        Validators,
+    };
+
+    fn request(headers: &str) -> Request {
+        format!("GET /a.css HTTP/1.1\r\n{headers}\r\n")
+            .into_bytes()
+            .into()
+    }
+
+    fn validators() -> Validators {
+        Validators {
+            etag: Some("\"abc\"".into()),
+            last_modified: Some(UNIX_EPOCH + Duration::from_millis(784_111_777_500)),
+        }
+    }
+
+    #[test]
+    fn test_make_etag() {
+        let modified = Some(UNIX_EPOCH + Duration::from_secs(16));
+        assert_eq!(None, make_etag(EtagMode::Off, 10, modified, None, b""));
+        assert_eq!(
+            Some("\"a-3b9aca000\"".to_string()),
+            make_etag(EtagMode::Strong, 10, modified, None, b"")
+        );
+        assert_eq!(
+            Some("W/\"2-a-3b9aca000\"".to_string()),
+            make_etag(EtagMode::Weak, 10, modified, Some(2), b"")
+        );
+        assert_ne!(
+            make_etag(EtagMode::Content, 2, modified, None, b"ab"),
+            make_etag(EtagMode::Content, 2, modified, None, b"ba")
+        );
+    }
+
+    #[test]
+    fn test_parse_etags() {
+        assert_eq!(
+            vec!["\"a\"", "W/\"b,c\"", "\"\""],
+            parse_etags(" \"a\" ,W/\"b,c\", \"\"")
+        );
+        assert!(weak_match("W/\"a\"", "\"a\""));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let validators = validators();
+        let eval = |headers: &str| evaluate(&request(headers), &validators);
+
+        assert_eq!(Precondition::Proceed, eval(""));
+        assert_eq!(
+            Precondition::NotModified,
+            eval("If-None-Match: \"x\", W/\"abc\"\r\n")
+        );
+        assert_eq!(Precondition::NotModified, eval("If-None-Match: *\r\n"));
+        assert_eq!(Precondition::Proceed, eval("If-None-Match: \"x\"\r\n"));
+        assert_eq!(
+            Precondition::NotModified,
+            eval("If-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n")
+        );
+        assert_eq!(
+            Precondition::Proceed,
+            eval("If-Modified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n")
+        );
+        // If-None-Match takes precedence over If-Modified-Since
+        assert_eq!(
+            Precondition::Proceed,
+            eval("If-None-Match: \"x\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n")
+        );
+        assert_eq!(Precondition::Failed, eval("If-Match: W/\"abc\"\r\n"));
+        assert_eq!(Precondition::Proceed, eval("If-Match: \"abc\"\r\n"));
+        assert_eq!(
+            Precondition::Failed,
+            eval("If-Unmodified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n")
+        );
+        // If-Match takes precedence over If-Unmodified-Since
+        assert_eq!(
+            Precondition::Proceed,
+            eval("If-Match: *\r\nIf-Unmodified-Since: Sun, 06 Nov 1994 08:49:36 GMT\r\n")
+        );
+    }
+
+    #[test]
+    fn test_if_range_matches() {
+        let validators = validators();
+        assert!(if_range_matches("\"abc\"", &validators));
+        assert!(!if_range_matches("W/\"abc\"", &validators));
+        assert!(if_range_matches(
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            &validators
+        ));
+        assert!(!if_range_matches(
+            "Sun, 06 Nov 1994 08:49:38 GMT",
+            &validators
+        ));
+        assert!(!if_range_matches(
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            &Validators::default()
+        ));
+    }
+}
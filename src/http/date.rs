@@ -0,0 +1,124 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// Format a time as an IMF-fixdate: `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn fmt_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let rem = secs % 86400;
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// Format a time for directory listings: `1994-11-06 08:49`
+pub fn fmt_listing_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60
+    )
+}
+
+// Parse an HTTP-date in any of the three formats allowed by RFC 9110:
+// `Sun, 06 Nov 1994 08:49:37 GMT`, `Sunday, 06-Nov-94 08:49:37 GMT`
+// and `Sun Nov  6 08:49:37 1994`
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let parts: Vec<&str> = value.split_whitespace().collect();
+    let (day, month, year, time) = match parts.as_slice() {
+        [_, day, month, year, time, "GMT"] => (*day, *month, year.parse().ok()?, *time),
+        [_, date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let (day, month) = (date.next()?, date.next()?);
+            let year: i64 = date.next()?.parse().ok()?;
+            let year = if year < 70 { year + 2000 } else { year + 1900 };
+            (day, month, year, *time)
+        }
+        [_, month, day, time, year] => (*day, *month, year.parse().ok()?, *time),
+        _ => return None,
+    };
+
+    let day: i64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let mut time = time.split(':').map(|t| t.parse::<u64>());
+    let (hour, min, sec) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if !(1..=31).contains(&day) || hour > 23 || min > 59 || sec > 60 {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + hour * 3600 + min * 60 + sec))
+}
+
+// Days since 1970-01-01 -> (year, month, day), from Howard Hinnant's date algorithms
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{fmt_http_date, parse_http_date};
+
+    #[test]
+    fn test_fmt_http_date() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!("Sun, 06 Nov 1994 08:49:37 GMT", fmt_http_date(time));
+        assert_eq!("Thu, 01 Jan 1970 00:00:00 GMT", fmt_http_date(UNIX_EPOCH));
+    }
+
+    #[test]
+    fn test_parse_http_date() {
+        let expected = Some(UNIX_EPOCH + Duration::from_secs(784111777));
+        assert_eq!(expected, parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert_eq!(expected, parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"));
+        assert_eq!(expected, parse_http_date("Sun Nov  6 08:49:37 1994"));
+        assert_eq!(None, parse_http_date("yesterday"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"));
+    }
+}
@@ -0,0 +1,212 @@
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+use brotli::CompressorWriter;
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use serde::Deserialize;
+
+use super::accept::parse_qvalues;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Encoding {
+    #[serde(rename = "identity")]
+    Identity,
+    #[serde(rename = "br")]
+    Brotli,
+    #[serde(rename = "gzip")]
+    Gzip,
+    // HTTP `deflate` is the zlib format (RFC 1950), not raw deflate
+    #[serde(rename = "deflate")]
+    Deflate,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Encoding::Identity => write!(f, "identity"),
+            Encoding::Brotli => write!(f, "br"),
+            Encoding::Gzip => write!(f, "gzip"),
+            Encoding::Deflate => write!(f, "deflate"),
+        }
+    }
+}
+
+impl Encoding {
+    // Suffix of precompressed siblings, `app.js.br` or `app.js.gz`
+    pub fn file_extension(&self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Identity | Encoding::Deflate => None,
+        }
+    }
+}
+
+// Pick the content coding to use for a response.
+// `supported` is in server preference order, which breaks q-value ties.
+pub fn negotiate(accept_encoding: Option<&str>, supported: &[Encoding]) -> Encoding {
+    let accepted = match accept_encoding {
+        Some(header) => parse_qvalues(header),
+        None => return Encoding::Identity,
+    };
+    let q_of = |name: &str| {
+        accepted
+            .iter()
+            .find(|(coding, _)| coding == name || (name == "gzip" && coding == "x-gzip"))
+            .or_else(|| accepted.iter().find(|(coding, _)| coding == "*"))
+            .map(|(_, q)| *q)
+    };
+
+    let mut best = Encoding::Identity;
+    let mut best_q = 0.0;
+    for encoding in supported {
+        let q = q_of(&encoding.to_string()).unwrap_or(0.0);
+        if q > best_q {
+            best = *encoding;
+            best_q = q;
+        }
+    }
+    best
+}
+
+// Types that are already compressed or too small to benefit are left alone
+pub fn is_compressible(mime: &str) -> bool {
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(
+            mime,
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/wasm"
+                | "application/rtf"
+                | "application/vnd.ms-fontobject"
+                | "font/ttf"
+                | "font/otf"
+                | "image/bmp"
+                | "image/x-icon"
+        )
+}
+
+// Incremental compressor; compressed bytes are collected in memory and
+// handed out with `take_output` so they can be streamed as they are produced.
+pub enum Encoder {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    // `level` is 0-9; brotli quality is scaled onto its 0-11 range
+    pub fn new(encoding: Encoding, level: u32) -> Option<Self> {
+        let level = level.min(9);
+        match encoding {
+            Encoding::Identity => None,
+            Encoding::Brotli => Some(Self::Brotli(Box::new(CompressorWriter::new(
+                Vec::new(),
+                4096,
+                (level * 11).div_ceil(9),
+                22,
+            )))),
+            Encoding::Gzip => Some(Self::Gzip(GzEncoder::new(
+                Vec::new(),
+                Compression::new(level),
+            ))),
+            Encoding::Deflate => Some(Self::Deflate(ZlibEncoder::new(
+                Vec::new(),
+                Compression::new(level),
+            ))),
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Brotli(encoder) => encoder.write_all(data),
+            Encoder::Gzip(encoder) => encoder.write_all(data),
+            Encoder::Deflate(encoder) => encoder.write_all(data),
+        }
+    }
+
+    // Compressed bytes produced so far
+    pub fn take_output(&mut self) -> Vec<u8> {
+        match self {
+            Encoder::Brotli(encoder) => std::mem::take(encoder.get_mut()),
+            Encoder::Gzip(encoder) => std::mem::take(encoder.get_mut()),
+            Encoder::Deflate(encoder) => std::mem::take(encoder.get_mut()),
+        }
+    }
+
+    // Flush the stream trailer and return the remaining bytes
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Encoder::Brotli(encoder) => Ok(encoder.into_inner()),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Deflate(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use flate2::read::{GzDecoder, ZlibDecoder};
+
+    use super::{is_compressible, negotiate, Encoder, Encoding};
+
+    const ALL: [Encoding; 3] = [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate];
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(Encoding::Identity, negotiate(None, &ALL));
+        assert_eq!(Encoding::Brotli, negotiate(Some("gzip, deflate, br"), &ALL));
+        assert_eq!(Encoding::Gzip, negotiate(Some("br;q=0.5, gzip"), &ALL));
+        assert_eq!(Encoding::Gzip, negotiate(Some("x-gzip"), &ALL));
+        assert_eq!(Encoding::Deflate, negotiate(Some("deflate, br;q=0"), &ALL));
+        assert_eq!(Encoding::Brotli, negotiate(Some("*"), &ALL));
+        assert_eq!(Encoding::Identity, negotiate(Some("identity"), &ALL));
+        assert_eq!(Encoding::Identity, negotiate(Some("gzip;q=0"), &ALL));
+        assert_eq!(Encoding::Gzip, negotiate(Some("br, gzip"), &ALL[1..]));
+    }
+
+    #[test]
+    fn test_is_compressible() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(is_compressible("application/json"));
+        assert!(!is_compressible("video/mp4"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("font/woff2"));
+    }
+
+    #[test]
+    fn test_encoder_round_trip() {
+        let input = "hello compression ".repeat(1000);
+        for encoding in ALL {
+            let mut encoder = Encoder::new(encoding, 6).unwrap();
+            let mut output = Vec::new();
+            for block in input.as_bytes().chunks(1000) {
+                encoder.write(block).unwrap();
+                output.extend(encoder.take_output());
+            }
+            output.extend(encoder.finish().unwrap());
+            assert!(output.len() < input.len());
+
+            let mut decoded = String::new();
+            match encoding {
+                Encoding::Gzip => GzDecoder::new(&output[..]).read_to_string(&mut decoded),
+                Encoding::Deflate => ZlibDecoder::new(&output[..]).read_to_string(&mut decoded),
+                _ => brotli::Decompressor::new(&output[..], 4096).read_to_string(&mut decoded),
+            }
+            .unwrap();
+            assert_eq!(input, decoded);
+        }
+    }
+}
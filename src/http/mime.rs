@@ -0,0 +1,311 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+// Extension -> MIME type table used when none is configured
+const BUILTIN_TYPES: &[(&str, &[&str])] = &[
+    ("text/html", &["html", "htm", "shtml"]),
+    ("text/css", &["css"]),
+    ("text/xml", &["xml"]),
+    ("text/plain", &["txt", "log"]),
+    ("text/csv", &["csv"]),
+    ("text/markdown", &["md", "markdown"]),
+    ("text/javascript", &["js", "mjs"]),
+    ("application/json", &["json", "map"]),
+    ("application/ld+json", &["jsonld"]),
+    ("application/manifest+json", &["webmanifest"]),
+    ("application/xhtml+xml", &["xhtml"]),
+    ("application/rss+xml", &["rss"]),
+    ("application/atom+xml", &["atom"]),
+    ("application/wasm", &["wasm"]),
+    ("application/pdf", &["pdf"]),
+    ("application/rtf", &["rtf"]),
+    ("application/zip", &["zip"]),
+    ("application/gzip", &["gz"]),
+    ("application/x-tar", &["tar"]),
+    ("application/x-7z-compressed", &["7z"]),
+    ("application/vnd.rar", &["rar"]),
+    ("application/msword", &["doc"]),
+    (
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        &["docx"],
+    ),
+    ("application/vnd.ms-excel", &["xls"]),
+    (
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        &["xlsx"],
+    ),
+    ("application/vnd.ms-powerpoint", &["ppt"]),
+    (
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+        &["pptx"],
+    ),
+    ("image/png", &["png"]),
+    ("image/apng", &["apng"]),
+    ("image/jpeg", &["jpg", "jpeg"]),
+    ("image/gif", &["gif"]),
+    ("image/webp", &["webp"]),
+    ("image/avif", &["avif"]),
+    ("image/bmp", &["bmp"]),
+    ("image/tiff", &["tif", "tiff"]),
+    ("image/svg+xml", &["svg", "svgz"]),
+    ("image/x-icon", &["ico"]),
+    ("font/woff", &["woff"]),
+    ("font/woff2", &["woff2"]),
+    ("font/ttf", &["ttf"]),
+    ("font/otf", &["otf"]),
+    ("application/vnd.ms-fontobject", &["eot"]),
+    ("audio/mpeg", &["mp3"]),
+    ("audio/ogg", &["ogg", "oga"]),
+    ("audio/wav", &["wav"]),
+    ("audio/mp4", &["m4a"]),
+    ("audio/flac", &["flac"]),
+    ("video/mp4", &["mp4", "m4v"]),
+    ("video/webm", &["webm"]),
+    ("video/ogg", &["ogv"]),
+    ("video/quicktime", &["mov"]),
+    ("video/x-msvideo", &["avi"]),
+];
+
+// Registry mapping lowercase file extensions to MIME types
+#[derive(Debug, Clone)]
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+}
+
+impl Default for MimeTypes {
+    fn default() -> Self {
+        let mut mime_types = Self {
+            types: HashMap::new(),
+        };
+        for (mime, extensions) in BUILTIN_TYPES {
+            for ext in extensions.iter() {
+                mime_types.insert(ext, mime);
+            }
+        }
+        mime_types
+    }
+}
+
+impl MimeTypes {
+    pub fn insert(&mut self, extension: &str, mime: &str) {
+        self.types
+            .insert(extension.to_ascii_lowercase(), mime.to_string());
+    }
+
+    // MIME type for the extension of `path`, if known
+    pub fn lookup(&self, path: &Path) -> Option<&str> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        self.types.get(&ext).map(String::as_str)
+    }
+
+    // Merge an nginx-style `mime.types` file, overriding existing entries
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        self.parse(&content);
+        Ok(())
+    }
+
+    // types {
+    //     text/html  html htm;
+    //     image/png  png;
+    // }
+    // The surrounding `types { }` block is optional.
+    pub fn parse(&mut self, content: &str) {
+        let content: String = content
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .collect::<Vec<&str>>()
+            .join("\n");
+        let body = match (content.find('{'), content.rfind('}')) {
+            (Some(start), Some(end)) if start < end => &content[start + 1..end],
+            _ => &content[..],
+        };
+
+        for statement in body.split(';') {
+            let mut tokens = statement.split_whitespace();
+            if let Some(mime) = tokens.next() {
+                for ext in tokens {
+                    self.insert(ext, mime);
+                }
+            }
+        }
+    }
+}
+
+// Number of leading bytes inspected when sniffing
+const SNIFF_LEN: usize = 512;
+
+// Guess a MIME type from the magic bytes at the start of `content`.
+// HTML is never sniffed, so user uploads cannot turn into pages.
+pub fn sniff(content: &[u8]) -> Option<&'static str> {
+    if content.is_empty() {
+        return None;
+    }
+    let head = &content[..content.len().min(SNIFF_LEN)];
+    let signature = |offset: usize, magic: &[u8]| head[offset.min(head.len())..].starts_with(magic);
+
+    if signature(0, b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if signature(0, b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if signature(0, b"GIF87a") || signature(0, b"GIF89a") {
+        Some("image/gif")
+    } else if signature(0, b"RIFF") && signature(8, b"WEBP") {
+        Some("image/webp")
+    } else if signature(0, b"%PDF-") {
+        Some("application/pdf")
+    } else if signature(0, b"PK\x03\x04") || signature(0, b"PK\x05\x06") {
+        Some("application/zip")
+    } else if signature(0, b"\x1f\x8b\x08") {
+        Some("application/gzip")
+    } else if signature(4, b"ftyp") {
+        Some(iso_media_type(head.get(8..12).unwrap_or_default()))
+    } else if signature(0, b"wOFF") {
+        Some("font/woff")
+    } else if signature(0, b"wOF2") {
+        Some("font/woff2")
+    } else if is_utf8_text(head, content.len() > head.len()) {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+// Type of an ISO base media file (MP4, QuickTime, HEIF, AVIF) by the major
+// brand of its `ftyp` box. Unknown brands are left as binary data.
+fn iso_media_type(brand: &[u8]) -> &'static str {
+    match brand {
+        b"isom" | b"iso2" | b"iso4" | b"iso5" | b"iso6" | b"mp41" | b"mp42" | b"avc1" | b"dash"
+        | b"mmp4" | b"M4V " | b"M4VH" | b"M4VP" | b"f4v " => "video/mp4",
+        b"M4A " | b"M4B " | b"M4P " | b"F4A " => "audio/mp4",
+        b"qt  " => "video/quicktime",
+        b"heic" | b"heix" | b"heim" | b"heis" => "image/heic",
+        b"mif1" => "image/heif",
+        b"avif" | b"avis" => "image/avif",
+        [b'3', b'g', b'p', _] => "video/3gpp",
+        [b'3', b'g', b'2', _] => "video/3gpp2",
+        _ => "application/octet-stream",
+    }
+}
+
+// UTF-8 without control characters other than whitespace.
+// A multi-byte sequence cut off by the sniffing window is accepted.
+fn is_utf8_text(head: &[u8], truncated: bool) -> bool {
+    let valid = match std::str::from_utf8(head) {
+        Ok(_) => head,
+        Err(e) if truncated && e.error_len().is_none() => &head[..e.valid_up_to()],
+        Err(_) => return false,
+    };
+    valid
+        .iter()
+        .all(|&b| b >= 0x20 || matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+}
+
+// Whether a MIME type is textual and should carry a charset
+pub fn is_text(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime == "application/javascript"
+        || mime == "application/xml"
+        || mime.ends_with("+xml")
+}
+
+// Value for the `Content-Type` header, with a charset on text types only
+pub fn with_charset(mime: &str) -> String {
+    if is_text(mime) && !mime.contains("charset=") {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{sniff, with_charset, MimeTypes};
+
+    #[test]
+    fn test_lookup() {
+        let mime_types = MimeTypes::default();
+        assert_eq!(
+            Some("image/png"),
+            mime_types.lookup(Path::new("a/logo.PNG"))
+        );
+        assert_eq!(
+            Some("image/jpeg"),
+            mime_types.lookup(Path::new("photo.jpg"))
+        );
+        assert_eq!(
+            Some("application/pdf"),
+            mime_types.lookup(Path::new("x.pdf"))
+        );
+        assert_eq!(None, mime_types.lookup(Path::new("README")));
+        // `.htm` in a directory name must not match
+        assert_eq!(None, mime_types.lookup(Path::new("a.htm.d/file.unknown")));
+    }
+
+    #[test]
+    fn test_parse_nginx_mime_types() {
+        let mut mime_types = MimeTypes::default();
+        mime_types.parse(
+            "# comment\ntypes {\n    application/javascript  js;\n    text/x-rust rs  rlib;\n}\n",
+        );
+        assert_eq!(
+            Some("application/javascript"),
+            mime_types.lookup(Path::new("app.js"))
+        );
+        assert_eq!(Some("text/x-rust"), mime_types.lookup(Path::new("main.rs")));
+        assert_eq!(
+            Some("text/x-rust"),
+            mime_types.lookup(Path::new("lib.rlib"))
+        );
+    }
+
+    #[test]
+    fn test_with_charset() {
+        assert_eq!("text/css; charset=utf-8", with_charset("text/css"));
+        assert_eq!(
+            "image/svg+xml; charset=utf-8",
+            with_charset("image/svg+xml")
+        );
+        assert_eq!("image/png", with_charset("image/png"));
+        assert_eq!("application/json", with_charset("application/json"));
+    }
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(Some("image/png"), sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert_eq!(Some("image/jpeg"), sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"));
+        assert_eq!(Some("image/gif"), sniff(b"GIF89a\x01\0"));
+        assert_eq!(Some("image/webp"), sniff(b"RIFF\x24\0\0\0WEBPVP8 "));
+        assert_eq!(Some("application/pdf"), sniff(b"%PDF-1.7\n"));
+        assert_eq!(Some("application/zip"), sniff(b"PK\x03\x04\x14\0"));
+        assert_eq!(Some("application/gzip"), sniff(b"\x1f\x8b\x08\0"));
+        assert_eq!(Some("video/mp4"), sniff(b"\0\0\0\x20ftypisom\0\0\x02\0"));
+        assert_eq!(Some("audio/mp4"), sniff(b"\0\0\0\x20ftypM4A \0\0\0\0"));
+        assert_eq!(Some("image/heic"), sniff(b"\0\0\0\x18ftypheic\0\0\0\0mif1"));
+        assert_eq!(
+            Some("image/avif"),
+            sniff(b"\0\0\0\x1cftypavif\0\0\0\0avifmif1")
+        );
+        assert_eq!(
+            Some("video/quicktime"),
+            sniff(b"\0\0\0\x14ftypqt  \0\0\x02\0")
+        );
+        assert_eq!(
+            Some("application/octet-stream"),
+            sniff(b"\0\0\0\x14ftypcrx \0\0\0\x01")
+        );
+        assert_eq!(Some("font/woff2"), sniff(b"wOF2\0\x01\0\0"));
+        assert_eq!(Some("text/plain"), sniff("héllo\r\n\tworld".as_bytes()));
+        assert_eq!(Some("text/plain"), sniff(b"<html><body>hi</body></html>"));
+        assert_eq!(None, sniff(b"\x7fELF\x02\x01\x01\0"));
+        assert_eq!(None, sniff(b"\xc3\x28 invalid"));
+    }
+
+    #[test]
+    fn test_sniff_truncated_utf8() {
+        let mut content = vec![b'a'; 511];
+        content.extend_from_slice("中".as_bytes());
+        assert_eq!(Some("text/plain"), sniff(&content));
+    }
+}
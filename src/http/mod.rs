@@ -1,2 +1,11 @@
+pub mod accept;
+pub mod chunked;
+pub mod conditional;
+pub mod date;
+pub mod encoding;
+pub mod escape;
+pub mod mime;
+pub mod range;
 pub mod request;
 pub mod response;
+pub mod uri;
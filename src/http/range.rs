@@ -0,0 +1,214 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    ops::Range,
+};
+
+// More ranges than this in one request are ignored and the full body is sent
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    // No usable `Range` header, send the whole representation
+    Full,
+    // Sorted, non-overlapping byte ranges
+    Partial(Vec<Range<u64>>),
+    // None of the ranges overlap the representation
+    Unsatisfiable,
+}
+
+// Parse a `Range` header against a representation of `len` bytes.
+// Syntactically invalid headers are ignored as RFC 9110 requires.
+pub fn parse_range(header: &str, len: u64) -> RangeRequest {
+    let specs = match header.trim().strip_prefix("bytes=") {
+        Some(specs) => specs,
+        None => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs
+        .split(',')
+        .map(str::trim)
+        .filter(|spec| !spec.is_empty())
+    {
+        count += 1;
+        let (first, last) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return RangeRequest::Full,
+        };
+        let range = match (first.parse::<u64>(), last.parse::<u64>()) {
+            // bytes=-500, the last 500 bytes
+            (Err(_), Ok(suffix)) if first.is_empty() => {
+                if suffix == 0 || len == 0 {
+                    continue;
+                }
+                len.saturating_sub(suffix)..len
+            }
+            // bytes=9500-
+            (Ok(first), Err(_)) if last.is_empty() => first..len,
+            // bytes=0-499
+            (Ok(first), Ok(last)) if first <= last => first..len.min(last.saturating_add(1)),
+            _ => return RangeRequest::Full,
+        };
+        if range.start < len && !range.is_empty() {
+            ranges.push(range);
+        }
+    }
+
+    if count == 0 || count > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+    if ranges.is_empty() {
+        return RangeRequest::Unsatisfiable;
+    }
+
+    // Coalesce overlapping or adjacent ranges
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    RangeRequest::Partial(merged)
+}
+
+// Value of the `Content-Range` header for a satisfied range
+pub fn content_range(range: &Range<u64>, len: u64) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+// Random boundary for `multipart/byteranges` bodies
+pub fn boundary() -> String {
+    format!(
+        "minginx-{:016x}",
+        RandomState::new().build_hasher().finish()
+    )
+}
+
+// Headers opening the part of a `multipart/byteranges` body for `range`
+pub fn multipart_part_header(
+    range: &Range<u64>,
+    len: u64,
+    content_type: &str,
+    boundary: &str,
+) -> String {
+    format!(
+        "--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+        content_range(range, len)
+    )
+}
+
+pub fn multipart_trailer(boundary: &str) -> String {
+    format!("--{boundary}--\r\n")
+}
+
+// Size of a `multipart/byteranges` body, known before any part is read
+pub fn multipart_length(
+    ranges: &[Range<u64>],
+    len: u64,
+    content_type: &str,
+    boundary: &str,
+) -> u64 {
+    let parts: u64 = ranges
+        .iter()
+        .map(|range| {
+            multipart_part_header(range, len, content_type, boundary).len() as u64
+                + (range.end - range.start)
+                + 2
+        })
+        .sum();
+    parts + multipart_trailer(boundary).len() as u64
+}
+
+// Build a `multipart/byteranges` body with one part per range
+pub fn multipart_body(
+    content: &[u8],
+    ranges: &[Range<u64>],
+    content_type: &str,
+    boundary: &str,
+) -> Vec<u8> {
+    let len = content.len() as u64;
+    let mut body = Vec::new();
+    for range in ranges {
+        body.extend_from_slice(
+            multipart_part_header(range, len, content_type, boundary).as_bytes(),
+        );
+        body.extend_from_slice(&content[range.start as usize..range.end as usize]);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(multipart_trailer(boundary).as_bytes());
+    body
+}
+
+#[cfg(test)]
+mod test {
+    use super::{content_range, multipart_body, multipart_length, parse_range, RangeRequest};
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_parse_range() {
+        assert_eq!(
+            RangeRequest::Partial(vec![0..500]),
+            parse_range("bytes=0-499", 1000)
+        );
+        assert_eq!(
+            RangeRequest::Partial(vec![900..1000]),
+            parse_range("bytes=900-", 1000)
+        );
+        assert_eq!(
+            RangeRequest::Partial(vec![800..1000]),
+            parse_range("bytes=-200", 1000)
+        );
+        assert_eq!(
+            RangeRequest::Partial(vec![0..1000]),
+            parse_range("bytes=-2000", 1000)
+        );
+        assert_eq!(
+            RangeRequest::Partial(vec![990..1000]),
+            parse_range("bytes=990-5000", 1000)
+        );
+        assert_eq!(
+            RangeRequest::Partial(vec![0..10, 20..30]),
+            parse_range("bytes=20-29, 0-4, 3-9", 1000)
+        );
+        assert_eq!(
+            RangeRequest::Partial(vec![0..1000]),
+            parse_range("bytes=0-18446744073709551615", 1000)
+        );
+        assert_eq!(
+            RangeRequest::Partial(vec![0..u64::MAX]),
+            parse_range("bytes=0-18446744073709551615", u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_parse_range_invalid() {
+        assert_eq!(RangeRequest::Full, parse_range("items=0-1", 1000));
+        assert_eq!(RangeRequest::Full, parse_range("bytes=5-1", 1000));
+        assert_eq!(RangeRequest::Full, parse_range("bytes=abc", 1000));
+        assert_eq!(RangeRequest::Full, parse_range("bytes=", 1000));
+        assert_eq!(
+            RangeRequest::Unsatisfiable,
+            parse_range("bytes=1000-", 1000)
+        );
+        assert_eq!(RangeRequest::Unsatisfiable, parse_range("bytes=-0", 1000));
+        assert_eq!(RangeRequest::Unsatisfiable, parse_range("bytes=0-", 0));
+    }
+
+    #[test]
+    fn test_multipart_body() {
+        let body = multipart_body(b"0123456789", &[0..2, 5..7], "text/plain", "b");
+        let expected = "--b\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                        --b\r\nContent-Type: text/plain\r\nContent-Range: bytes 5-6/10\r\n\r\n56\r\n\
+                        --b--\r\n";
+        assert_eq!(expected, String::from_utf8_lossy(&body));
+        assert_eq!(
+            body.len() as u64,
+            multipart_length(&[0..2, 5..7], 10, "text/plain", "b")
+        );
+        assert_eq!("bytes 5-6/10", content_range(&(5..7), 10));
+    }
+}
@@ -1,4 +1,4 @@
-use super::response::HttpVersion;
+use super::{response::HttpVersion, uri::percent_decode};
 
 use std::{collections::HashMap, fmt};
 
@@ -7,6 +7,8 @@ pub struct Request {
     method: HttpMethod,
     path: String,
     version: HttpVersion,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
 }
 
 impl Request {
@@ -16,21 +18,67 @@ impl Request {
             method: HttpMethod::Get,
             path: "".into(),
             version: HttpVersion::V1_1,
+            headers: Vec::new(),
+            body: Vec::new(),
         }
     }
-    #[allow(unused)]
     pub fn method(&self) -> HttpMethod {
-        self.method
+        self.method.clone()
     }
 
     pub fn path(&self) -> String {
         self.path.clone()
     }
 
-    #[allow(unused)]
+    // Request path without the query string
+    pub fn uri_path(&self) -> &str {
+        self.path.split('?').next().unwrap_or_default()
+    }
+
+    // Raw query string, without the leading `?`
+    pub fn query(&self) -> Option<&str> {
+        self.path.split_once('?').map(|(_, query)| query)
+    }
+
+    // Value of `name` in an `a=1&b=2` query string, percent-decoded
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query()?.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(key) == name).then(|| percent_decode(&value.replace('+', " ")))
+        })
+    }
+
+    // Value of the cookie `name` sent in `Cookie` headers
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, v)| v.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
     pub fn version(&self) -> HttpVersion {
         self.version
     }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    // Bytes following the header block
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    // Value of the first header named `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
 }
 
 impl Request {
@@ -87,34 +135,67 @@ impl Request {
 impl From<Vec<u8>> for Request {
     fn from(req: Vec<u8>) -> Self {
         // GET / HTTP/1.1
-        let req_string = String::from_utf8_lossy(&req);
+        // Host: localhost
+        let (head, body) = match req.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => (&req[..end + 2], req[end + 4..].to_vec()),
+            None => (&req[..], Vec::new()),
+        };
+        let req_string = String::from_utf8_lossy(head);
+        let mut lines = req_string.split("\r\n");
 
-        let mut req_string = req_string.split_whitespace();
+        let mut request_line = lines.next().unwrap_or_default().split_whitespace();
 
-        let method = req_string.next().unwrap_or_default().into();
-        let path = req_string.next().unwrap_or_default();
-        let version = req_string.next().unwrap_or_default().into();
+        let method = request_line.next().unwrap_or_default().into();
+        let path = request_line.next().unwrap_or_default();
+        let version = request_line.next().unwrap_or_default().into();
+
+        let headers = lines
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .map(|(k, v)| {
+                (
+                    k.trim().to_string(),
+                    v.trim_matches(|c: char| c.is_whitespace() || c == '\0')
+                        .to_string(),
+                )
+            })
+            .collect();
 
         Self {
             method,
             path: path.to_string(),
             version,
+            headers,
+            body,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum HttpMethod {
     Get,
+    Head,
     Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    // Any other token, kept as sent so it can be passed on unchanged
+    Other(String),
 }
 
 impl From<&str> for HttpMethod {
+    // Method names are case-sensitive
     fn from(value: &str) -> Self {
-        match value.to_ascii_uppercase().as_str() {
+        match value {
             "GET" => Self::Get,
+            "HEAD" => Self::Head,
             "POST" => Self::Post,
-            _ => Self::Get,
+            "PUT" => Self::Put,
+            "DELETE" => Self::Delete,
+            "PATCH" => Self::Patch,
+            "OPTIONS" => Self::Options,
+            other => Self::Other(other.to_string()),
         }
     }
 }
@@ -123,7 +204,13 @@ impl fmt::Display for HttpMethod {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             HttpMethod::Get => write!(f, "GET"),
+            HttpMethod::Head => write!(f, "HEAD"),
             HttpMethod::Post => write!(f, "POST"),
+            HttpMethod::Put => write!(f, "PUT"),
+            HttpMethod::Delete => write!(f, "DELETE"),
+            HttpMethod::Patch => write!(f, "PATCH"),
+            HttpMethod::Options => write!(f, "OPTIONS"),
+            HttpMethod::Other(method) => write!(f, "{}", method),
         }
     }
 }
@@ -140,6 +227,8 @@ mod test {
             method: super::HttpMethod::Get,
             path: "/test".into(),
             version: crate::http::response::HttpVersion::V1_1,
+            headers: Vec::new(),
+            body: Vec::new(),
         };
         let req = b"GET /test HTTP/1.1".to_vec();
         let req_parsed = req.into();
@@ -162,9 +251,62 @@ mod test {
             method: super::HttpMethod::Get,
             path: "get?a=b?aa=bb".into(),
             version: crate::http::response::HttpVersion::V1_1,
+            headers: Vec::new(),
+            body: Vec::new(),
         };
         let parsed = req.parse_queries();
         println!("{:?}", parsed);
         assert_eq!(expected, parsed);
     }
+
+    #[test]
+    fn test_parse_headers() {
+        let mut req =
+            b"GET /video.mp4 HTTP/1.1\r\nHost: localhost\r\nrange:  bytes=0-99\r\n\r\n".to_vec();
+        req.resize(1024, 0);
+        let req: Request = req.into();
+        assert_eq!(Some("localhost"), req.header("host"));
+        assert_eq!(Some("bytes=0-99"), req.header("Range"));
+        assert_eq!(None, req.header("If-Range"));
+    }
+
+    #[test]
+    fn test_parse_body() {
+        let req: Request = b"POST /a.php HTTP/1.1\r\nContent-Length: 7\r\n\r\na=1\r\n\r\nb"
+            .to_vec()
+            .into();
+        assert_eq!(super::HttpMethod::Post, req.method());
+        assert_eq!(Some("7"), req.header("Content-Length"));
+        assert_eq!(b"a=1\r\n\r\nb", req.body());
+    }
+
+    #[test]
+    fn test_parse_method() {
+        let req: Request = b"PROPFIND /dav/ HTTP/1.1".to_vec().into();
+        assert_eq!(super::HttpMethod::Other("PROPFIND".into()), req.method());
+        assert_eq!("PROPFIND", req.method().to_string());
+        let req: Request = b"get / HTTP/1.1".to_vec().into();
+        assert_ne!(super::HttpMethod::Get, req.method());
+        assert_eq!("get", req.method().to_string());
+    }
+
+    #[test]
+    fn test_query_param() {
+        let req: Request = b"GET /dir/?sort=size&order=desc&q=a%20b+c HTTP/1.1"
+            .to_vec()
+            .into();
+        assert_eq!(Some("size".into()), req.query_param("sort"));
+        assert_eq!(Some("a b c".into()), req.query_param("q"));
+        assert_eq!(None, req.query_param("archive"));
+    }
+
+    #[test]
+    fn test_cookie() {
+        let req: Request = b"GET / HTTP/1.1\r\nCookie: a=1; session=xyz\r\ncookie: b=2\r\n\r\n"
+            .to_vec()
+            .into();
+        assert_eq!(Some("xyz"), req.cookie("session"));
+        assert_eq!(Some("2"), req.cookie("b"));
+        assert_eq!(None, req.cookie("sess"));
+    }
 }
@@ -1,10 +1,10 @@
-use std::{collections::HashMap, fmt};
+use std::fmt;
 
 #[derive(Debug)]
 pub struct Response<'a> {
     version: HttpVersion,
     status: HttpStatus,
-    headers: HashMap<String, String>, // Content-Type: text/html
+    headers: Vec<(String, String)>, // Content-Type: text/html, kept in insertion order
     body: &'a [u8],
 }
 
@@ -18,8 +18,16 @@ impl Response<'_> {
         self.status
     }
 
-    pub fn headers(&self) -> HashMap<String, String> {
-        self.headers.clone()
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    // Value of the header named `name` (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
     }
 
     // HTTP headers (&str)
@@ -35,18 +43,22 @@ impl Response<'_> {
         self.body
     }
 
-    // Response as bytes
-    pub fn as_bytes(&self) -> Vec<u8> {
-        let res = format!(
-            "{} {}\r\n{}\r\n",
+    // Status line and headers, for bodies that are streamed separately
+    pub fn head_bytes(&self) -> Vec<u8> {
+        // The space after the code is required even without a reason phrase
+        format!(
+            "{} {} {}\r\n{}\r\n",
             self.version(),
-            self.status(),
+            self.status().code(),
+            self.status().reason(),
             self.headers_http()
         )
-        .as_bytes()
-        .to_owned();
-        let res = [res, self.body().to_vec()].concat();
-        res
+        .into_bytes()
+    }
+
+    // Response as bytes
+    pub fn as_bytes(&self) -> Vec<u8> {
+        [self.head_bytes(), self.body().to_vec()].concat()
     }
 }
 
@@ -56,7 +68,7 @@ impl<'a> Response<'a> {
         Self {
             version: HttpVersion::V1_1,
             status: HttpStatus::Ok,
-            headers: HashMap::new(),
+            headers: Vec::new(),
             body: b"",
         }
     }
@@ -72,12 +84,48 @@ impl<'a> Response<'a> {
         self
     }
 
+    // Replaces any header with the same (case-insensitive) name
     pub fn set_headers(&mut self, key: String, value: String) -> &mut Self {
-        self.headers.insert(key, value);
+        match self
+            .headers
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(&key))
+        {
+            Some(header) => header.1 = value,
+            None => self.headers.push((key, value)),
+        }
+        self
+    }
+
+    // Append a field even if one with that name is already set, as needed
+    // for `Set-Cookie`
+    pub fn add_header(&mut self, key: String, value: String) -> &mut Self {
+        self.headers.push((key, value));
+        self
+    }
+
+    pub fn remove_header(&mut self, key: &str) -> &mut Self {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
         self
     }
 
-    pub fn set_body(&'a mut self, body: &'a [u8]) -> &mut Self {
+    // Add a field to `Vary`, keeping the ones already listed
+    pub fn add_vary(&mut self, field: &str) -> &mut Self {
+        let vary = match self.header("Vary") {
+            Some(vary)
+                if vary
+                    .split(',')
+                    .any(|f| f.trim().eq_ignore_ascii_case(field)) =>
+            {
+                return self
+            }
+            Some(vary) => format!("{vary}, {field}"),
+            None => field.to_string(),
+        };
+        self.set_headers("Vary".into(), vary)
+    }
+
+    pub fn set_body(&'a mut self, body: &'a [u8]) -> &'a mut Self {
         self.body = body;
         self
     }
@@ -105,23 +153,145 @@ impl From<&str> for HttpVersion {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpStatus {
     Ok,
+    Created,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    Unauthorized,
+    Forbidden,
     NotFound,
-    #[allow(unused)]
+    MethodNotAllowed,
+    LengthRequired,
+    PreconditionFailed,
+    ContentTooLarge,
+    RangeNotSatisfiable,
+    RequestHeaderFieldsTooLarge,
     BadRequest,
-    #[allow(unused)]
     InternalServerError,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    // Any other code, sent without a reason phrase
+    Other(u16),
+}
+
+impl HttpStatus {
+    pub fn code(&self) -> u16 {
+        match self {
+            HttpStatus::Ok => 200,
+            HttpStatus::Created => 201,
+            HttpStatus::NoContent => 204,
+            HttpStatus::PartialContent => 206,
+            HttpStatus::MovedPermanently => 301,
+            HttpStatus::Found => 302,
+            HttpStatus::SeeOther => 303,
+            HttpStatus::NotModified => 304,
+            HttpStatus::TemporaryRedirect => 307,
+            HttpStatus::PermanentRedirect => 308,
+            HttpStatus::BadRequest => 400,
+            HttpStatus::Unauthorized => 401,
+            HttpStatus::Forbidden => 403,
+            HttpStatus::NotFound => 404,
+            HttpStatus::MethodNotAllowed => 405,
+            HttpStatus::LengthRequired => 411,
+            HttpStatus::PreconditionFailed => 412,
+            HttpStatus::ContentTooLarge => 413,
+            HttpStatus::RangeNotSatisfiable => 416,
+            HttpStatus::RequestHeaderFieldsTooLarge => 431,
+            HttpStatus::InternalServerError => 500,
+            HttpStatus::BadGateway => 502,
+            HttpStatus::ServiceUnavailable => 503,
+            HttpStatus::GatewayTimeout => 504,
+            HttpStatus::Other(code) => *code,
+        }
+    }
+
+    // `None` for codes outside 100-599, which a status line cannot carry
+    pub fn from_code(code: u16) -> Option<Self> {
+        if !(100..600).contains(&code) {
+            return None;
+        }
+        let status = [
+            HttpStatus::Ok,
+            HttpStatus::Created,
+            HttpStatus::NoContent,
+            HttpStatus::PartialContent,
+            HttpStatus::MovedPermanently,
+            HttpStatus::Found,
+            HttpStatus::SeeOther,
+            HttpStatus::NotModified,
+            HttpStatus::TemporaryRedirect,
+            HttpStatus::PermanentRedirect,
+            HttpStatus::BadRequest,
+            HttpStatus::Unauthorized,
+            HttpStatus::Forbidden,
+            HttpStatus::NotFound,
+            HttpStatus::MethodNotAllowed,
+            HttpStatus::LengthRequired,
+            HttpStatus::PreconditionFailed,
+            HttpStatus::ContentTooLarge,
+            HttpStatus::RangeNotSatisfiable,
+            HttpStatus::RequestHeaderFieldsTooLarge,
+            HttpStatus::InternalServerError,
+            HttpStatus::BadGateway,
+            HttpStatus::ServiceUnavailable,
+            HttpStatus::GatewayTimeout,
+        ]
+        .into_iter()
+        .find(|status| status.code() == code)
+        .unwrap_or(HttpStatus::Other(code));
+        Some(status)
+    }
+
+    // 1xx, 204 and 304 responses never carry a body
+    pub fn allows_body(&self) -> bool {
+        !matches!(self.code(), 100..=199 | 204 | 304)
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HttpStatus::Ok => "OK",
+            HttpStatus::Created => "Created",
+            HttpStatus::NoContent => "No Content",
+            HttpStatus::PartialContent => "Partial Content",
+            HttpStatus::MovedPermanently => "Moved Permanently",
+            HttpStatus::Found => "Found",
+            HttpStatus::SeeOther => "See Other",
+            HttpStatus::NotModified => "Not Modified",
+            HttpStatus::TemporaryRedirect => "Temporary Redirect",
+            HttpStatus::PermanentRedirect => "Permanent Redirect",
+            HttpStatus::BadRequest => "Bad Request",
+            HttpStatus::Unauthorized => "Unauthorized",
+            HttpStatus::Forbidden => "Forbidden",
+            HttpStatus::NotFound => "Not Found",
+            HttpStatus::MethodNotAllowed => "Method Not Allowed",
+            HttpStatus::LengthRequired => "Length Required",
+            HttpStatus::PreconditionFailed => "Precondition Failed",
+            HttpStatus::ContentTooLarge => "Content Too Large",
+            HttpStatus::RangeNotSatisfiable => "Range Not Satisfiable",
+            HttpStatus::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            HttpStatus::InternalServerError => "Internal Server Error",
+            HttpStatus::BadGateway => "Bad Gateway",
+            HttpStatus::ServiceUnavailable => "Service Unavailable",
+            HttpStatus::GatewayTimeout => "Gateway Timeout",
+            HttpStatus::Other(_) => "",
+        }
+    }
 }
 
 impl fmt::Display for HttpStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            HttpStatus::Ok => write!(f, "200 OK"),
-            HttpStatus::NotFound => write!(f, "404 Not Found"),
-            HttpStatus::BadRequest => write!(f, "400 Bad Request"),
-            HttpStatus::InternalServerError => write!(f, "500 Internal Server Error"),
+        match self.reason() {
+            "" => write!(f, "{}", self.code()),
+            reason => write!(f, "{} {}", self.code(), reason),
         }
     }
 }
@@ -172,6 +342,10 @@ mod test {
         let expected = "500 Internal Server Error".to_string();
         let res = HttpStatus::InternalServerError;
         assert_eq!(expected, res.to_string());
+        assert_eq!(Some(HttpStatus::Found), HttpStatus::from_code(302));
+        assert_eq!("418", HttpStatus::from_code(418).unwrap().to_string());
+        assert_eq!(None, HttpStatus::from_code(0));
+        assert_eq!(None, HttpStatus::from_code(1000));
     }
 
     #[test]
@@ -201,4 +375,13 @@ mod test {
 
         assert_eq!(expected, res_http);
     }
+
+    #[test]
+    fn test_add_vary() {
+        let mut res = Response::new();
+        res.add_vary("Accept-Encoding")
+            .add_vary("Cookie")
+            .add_vary("accept-encoding");
+        assert_eq!(Some("Accept-Encoding, Cookie"), res.header("vary"));
+    }
 }
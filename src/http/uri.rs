@@ -0,0 +1,74 @@
+// Decode `%XX` escapes in a request path, leaving malformed escapes untouched
+pub fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let (Some(hi), Some(lo)) = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                decoded.push(hi << 4 | lo);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Encode a single path segment for use in a URL, keeping only unreserved characters
+pub fn percent_encode(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+// Whether a request path has `.` or `..` segments, once decoded. Such
+// paths are refused rather than normalized, so a prefix a request matched
+// is the one the handler and any upstream see.
+pub fn has_dot_segment(uri_path: &str) -> bool {
+    percent_decode(uri_path)
+        .split(['/', '\\'])
+        .any(|segment| segment == "." || segment == "..")
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{has_dot_segment, percent_decode, percent_encode};
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!("/a b/c", percent_decode("/a%20b/c"));
+        assert_eq!("/%zz/%4", percent_decode("/%zz/%4"));
+        assert_eq!("/中", percent_decode("/%E4%B8%AD"));
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!("a%20b%23%3F.txt", percent_encode("a b#?.txt"));
+        assert_eq!("%E4%B8%AD", percent_encode("中"));
+        assert_eq!("中", percent_decode(&percent_encode("中")));
+    }
+
+    #[test]
+    fn test_has_dot_segment() {
+        assert!(has_dot_segment("/api/../admin"));
+        assert!(has_dot_segment("/api/%2e%2e/admin"));
+        assert!(has_dot_segment("/api/..%2Fadmin"));
+        assert!(has_dot_segment("/./a"));
+        assert!(has_dot_segment("/a/.."));
+        assert!(!has_dot_segment("/a/..b/.c/file.tar.gz"));
+        assert!(!has_dot_segment("/"));
+    }
+}
@@ -1,28 +1,52 @@
-#![allow(clippy::unused_io_amount)]
 use std::{
     net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
     sync::{Arc, Mutex},
 };
 
 use log::{error, info, LevelFilter};
 
 use tokio::{
-    io::AsyncReadExt,
     net::{TcpListener, TcpStream},
+    sync::Semaphore,
 };
 
 use clap::{App, Arg};
 
 use env_logger::Builder;
 
+mod config;
+mod glob;
 mod http;
 mod server;
 
-use server::handlers::{Echo, Handler, StaticFile, VisitCount, PHPFile};
+use config::Config;
+use http::{
+    request::{HttpMethod, Request},
+    response::HttpStatus,
+    uri::percent_decode,
+};
+
+use server::{
+    balancer::Upstreams,
+    cache::FileCache,
+    cgi::{is_php_script, leads_to_script},
+    error::HttpError,
+    fastcgi::FastCgiClient,
+    handlers::{CgiFile, Echo, Gateway, Handler, PHPFile, Proxy, StaticFile, Status, VisitCount},
+    health::spawn_health_checks,
+    reader::{read_body, read_request_head, ReadError},
+};
 
 #[derive(Clone, Default)]
 pub struct SharedData {
     pub visit_count: u32,
+    pub file_cache: FileCache,
+    pub fastcgi: Option<Arc<FastCgiClient>>,
+    // Slots for running script processes, `None` without a limit
+    pub cgi_slots: Option<Arc<Semaphore>>,
+    pub upstreams: Arc<Upstreams>,
 }
 
 #[tokio::main]
@@ -55,46 +79,91 @@ async fn main() {
                 .help("Enables php")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("root")
+                .long("root")
+                .value_name("DIR")
+                .help("Sets the document root")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("config")
+                .short('c')
+                .long("config")
+                .value_name("FILE")
+                .help("Loads settings from a TOML file")
+                .takes_value(true),
+        )
         .get_matches();
 
     if matches.is_present("log") {
         Builder::new().filter(None, LevelFilter::Info).init();
     }
 
-    let mut php_on: bool = false;
+    let mut config = match matches.value_of("config") {
+        Some(file) => Config::load(Path::new(file)).unwrap_or_else(|e| {
+            eprintln!("Failed to load config {}: {}", file, e);
+            process::exit(1);
+        }),
+        None => Config::default(),
+    };
 
     if matches.is_present("php") {
-        php_on = true;
+        config.php = true;
+    }
+    if let Some(root) = matches.value_of("root") {
+        config.root = PathBuf::from(root);
+    }
+    if let Some(port) = matches.value_of("port") {
+        config.port = port.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid port: {}", port);
+            process::exit(1);
+        });
     }
 
-    let port = matches.value_of("port").unwrap_or("5000");
+    let port = config.port;
+    let config = Arc::new(config);
 
     let listener = TcpListener::bind(format!("127.0.0.1:{}", port))
         .await
         .unwrap();
     println!("Server start at http://127.0.0.1:{}", port);
 
-    let shared_data = Arc::new(Mutex::new(SharedData { visit_count: 0 }));
+    let shared_data = Arc::new(Mutex::new(SharedData {
+        visit_count: 0,
+        file_cache: FileCache::new(&config.file_cache),
+        fastcgi: FastCgiClient::new(&config.fastcgi).map(Arc::new),
+        cgi_slots: (config.cgi.max_processes > 0)
+            .then(|| Arc::new(Semaphore::new(config.cgi.max_processes))),
+        upstreams: Arc::new(Upstreams::new(&config.upstreams)),
+    }));
+    spawn_health_checks(&shared_data.lock().unwrap().upstreams);
     loop {
         match listener.accept().await {
             Ok((mut stream, addr)) => {
                 info!("New connection accepted");
                 let shared_data = Arc::clone(&shared_data);
+                let config = Arc::clone(&config);
 
                 tokio::spawn(async move {
-                    let mut buffer = [0; 1024];
-
-                    stream.read(&mut buffer).await.unwrap();
-                    //info!("{}", String::from_utf8_lossy(&buffer));
+                    let buffer = match read_request(&mut stream, &config).await {
+                        Ok(buffer) => buffer,
+                        Err(e) => {
+                            info!("Bad request from {}: {}", addr, e);
+                            if let Some(status) = e.status() {
+                                let handler = HttpError {
+                                    status,
+                                    path_buf: &[],
+                                    config: &config,
+                                };
+                                handler.handle(&mut stream, shared_data).await;
+                            }
+                            return;
+                        }
+                    };
                     // Log the client's information
                     log_client_info(addr, &buffer);
-                    let buf = String::from_utf8_lossy(&buffer);
-                    if php_on && buf.contains("php"){
-                        let handler = PHPFile { path_buf: &buffer };
-                        handler.handle(&mut stream, shared_data).await;
-                    } else {
-                        route(&mut stream, &buffer, shared_data).await;
-                    }
+                    route(&mut stream, &buffer, &config, shared_data).await;
                 });
             }
             Err(e) => {
@@ -104,27 +173,106 @@ async fn main() {
     }
 }
 
-async fn route(stream: &mut TcpStream, buffer: &[u8], shared_data: Arc<Mutex<SharedData>>) {
+// Read a request. Proxies and gateways stream the body themselves, so only
+// the head is read for them.
+async fn read_request(stream: &mut TcpStream, config: &Config) -> Result<Vec<u8>, ReadError> {
+    let buffer = read_request_head(stream).await?;
+    let req: Request = buffer.clone().into();
+    let uri_path = percent_decode(req.uri_path());
+    if config.proxy(&uri_path).is_some() || config.gateway(&uri_path).is_some() {
+        return Ok(buffer);
+    }
+    read_body(stream, buffer, config.client_max_body_size).await
+}
+
+async fn route(
+    stream: &mut TcpStream,
+    buffer: &[u8],
+    config: &Config,
+    shared_data: Arc<Mutex<SharedData>>,
+) {
+    let req: Request = buffer.to_vec().into();
+    let uri_path = percent_decode(req.uri_path());
+    if let Some(proxy) = config.proxy(&uri_path) {
+        let handler = Proxy {
+            path_buf: buffer,
+            config,
+            proxy,
+        };
+        return handler.handle(stream, shared_data).await;
+    }
+    if let Some(gateway) = config.gateway(&uri_path) {
+        let handler = Gateway {
+            path_buf: buffer,
+            config,
+            gateway,
+            redirects: 0,
+        };
+        return handler.handle(stream, shared_data).await;
+    }
+    if config.cgi.handles(req.uri_path()) {
+        let handler = CgiFile {
+            path_buf: buffer,
+            config,
+            redirects: 0,
+        };
+        return handler.handle(stream, shared_data).await;
+    }
+    // Scripts are told apart by their extension, `/index.php/users/1` included
+    if config.php && leads_to_script(req.uri_path(), is_php_script) {
+        let handler = PHPFile {
+            path_buf: buffer,
+            config,
+            redirects: 0,
+        };
+        return handler.handle(stream, shared_data).await;
+    }
     if buffer.starts_with(b"GET /") {
         if buffer.starts_with(b"GET /count") {
             VisitCount.handle(stream, shared_data).await;
+        } else if buffer.starts_with(b"GET /status ") {
+            Status.handle(stream, shared_data).await;
         } else if buffer.starts_with(b"GET /echo") {
             // Echo
             let handler = Echo { path_buf: buffer };
             handler.handle(stream, shared_data).await;
         } else {
-            let handler = StaticFile { path_buf: buffer };
+            let handler = StaticFile {
+                path_buf: buffer,
+                config,
+                redirects: 0,
+            };
             handler.handle(stream, shared_data).await;
         }
+    } else if req.method() == HttpMethod::Head {
+        // Files answer HEAD like GET, without the body
+        let handler = StaticFile {
+            path_buf: buffer,
+            config,
+            redirects: 0,
+        };
+        handler.handle(stream, shared_data).await;
+    } else if config.php && config.php_front_controller.is_some() {
+        // Frameworks route other methods through their front controller too
+        let handler = PHPFile {
+            path_buf: buffer,
+            config,
+            redirects: 0,
+        };
+        handler.front_controller(stream, shared_data).await;
+    } else {
+        let handler = HttpError {
+            status: HttpStatus::MethodNotAllowed,
+            path_buf: buffer,
+            config,
+        };
+        handler.handle(stream, shared_data).await;
     }
 }
 
 fn log_client_info(addr: SocketAddr, buffer: &[u8]) {
-    let request_line = std::str::from_utf8(buffer)
-        .unwrap_or_default()
-        .lines()
-        .next()
-        .unwrap_or_default();
+    let request = String::from_utf8_lossy(buffer);
+    let request_line = request.lines().next().unwrap_or_default();
 
     info!("Client {}: {}", addr, request_line);
 }
@@ -0,0 +1,607 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use flate2::Crc;
+use serde::Deserialize;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    config::Access,
+    http::{
+        date::civil_from_days,
+        encoding::{Encoder, Encoding},
+    },
+};
+
+use super::send::write_chunk;
+
+// Size of the blocks files are read in and chunks are flushed at
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// Downloading a directory as one archive with `?archive=zip` or `?archive=tar.gz`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    pub enabled: bool,
+    // Largest total size of the files in one archive, in bytes
+    pub max_size: u64,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+}
+
+impl ArchiveFormat {
+    // `?archive=` value
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "zip" => Some(ArchiveFormat::Zip),
+            "tar.gz" | "tgz" => Some(ArchiveFormat::TarGz),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::TarGz => "application/gzip",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+        }
+    }
+}
+
+// A file or directory going into an archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub path: PathBuf,
+    // Relative to the archived directory, `/`-separated, directories end in `/`
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+// Everything below `dir`, which is served at the decoded `uri_path`, in name
+// order. Denied paths are skipped, and so are symlinks so that the archive
+// cannot reach outside the tree or loop.
+pub async fn collect(dir: &Path, uri_path: &str, access: &Access) -> io::Result<Vec<ArchiveEntry>> {
+    let mut entries = Vec::new();
+    let mut pending = vec![(dir.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        let mut dir_entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = dir_entries.next_entry().await? {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let name = format!("{prefix}{file_name}");
+            if access.denied(&format!("{uri_path}{name}")) {
+                continue;
+            }
+            let metadata = entry.metadata().await?;
+            let modified = metadata.modified().unwrap_or(UNIX_EPOCH);
+            if metadata.is_dir() {
+                let name = format!("{name}/");
+                pending.push((entry.path(), name.clone()));
+                entries.push(ArchiveEntry {
+                    path: entry.path(),
+                    name,
+                    is_dir: true,
+                    size: 0,
+                    modified,
+                });
+            } else if metadata.is_file() {
+                entries.push(ArchiveEntry {
+                    path: entry.path(),
+                    name,
+                    is_dir: false,
+                    size: metadata.len(),
+                    modified,
+                });
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+// Write `entries` as a chunked response body in `format`
+pub async fn write_archive(
+    stream: &mut TcpStream,
+    format: ArchiveFormat,
+    entries: &[ArchiveEntry],
+    level: u32,
+) -> io::Result<()> {
+    let mut output = Output {
+        stream,
+        encoder: None,
+        buffer: Vec::with_capacity(BLOCK_SIZE),
+        written: 0,
+    };
+    match format {
+        ArchiveFormat::Zip => write_zip(&mut output, entries).await?,
+        ArchiveFormat::TarGz => {
+            output.encoder = Encoder::new(Encoding::Gzip, level);
+            write_tar(&mut output, entries).await?;
+        }
+    }
+    output.finish().await
+}
+
+// Chunked response body, optionally gzipped. `written` counts the bytes of
+// the archive itself, before compression, for the offsets ZIP records.
+struct Output<'a> {
+    stream: &'a mut TcpStream,
+    encoder: Option<Encoder>,
+    buffer: Vec<u8>,
+    written: u64,
+}
+
+impl Output<'_> {
+    async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.written += data.len() as u64;
+        match &mut self.encoder {
+            Some(encoder) => {
+                encoder.write(data)?;
+                self.buffer.extend(encoder.take_output());
+            }
+            None => self.buffer.extend_from_slice(data),
+        }
+        if self.buffer.len() >= BLOCK_SIZE {
+            write_chunk(self.stream, &self.buffer).await?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self) -> io::Result<()> {
+        if let Some(encoder) = self.encoder.take() {
+            self.buffer.extend(encoder.finish()?);
+        }
+        write_chunk(self.stream, &self.buffer).await?;
+        self.stream.write_all(b"0\r\n\r\n").await?;
+        self.stream.flush().await
+    }
+
+    // Copy `size` bytes of the file at `path`, padding with zeros if it
+    // shrank meanwhile so the sizes already written stay true
+    async fn copy_file(
+        &mut self,
+        path: &Path,
+        size: u64,
+        mut crc: Option<&mut Crc>,
+    ) -> io::Result<()> {
+        let mut file = fs::File::open(path).await?.take(size);
+        let mut block = vec![0; BLOCK_SIZE];
+        let mut remaining = size;
+        loop {
+            let read = file.read(&mut block).await?;
+            if read == 0 {
+                break;
+            }
+            if let Some(crc) = crc.as_deref_mut() {
+                crc.update(&block[..read]);
+            }
+            self.write(&block[..read]).await?;
+            remaining -= read as u64;
+        }
+        block.fill(0);
+        while remaining > 0 {
+            let size = remaining.min(BLOCK_SIZE as u64) as usize;
+            if let Some(crc) = crc.as_deref_mut() {
+                crc.update(&block[..size]);
+            }
+            self.write(&block[..size]).await?;
+            remaining -= size as u64;
+        }
+        Ok(())
+    }
+}
+
+// ZIP entries are stored uncompressed. Sizes are known up front but the CRC
+// is not, so each one follows its file in a data descriptor; ZIP64 records
+// are used for files, offsets and counts beyond the 32-bit limits.
+async fn write_zip(output: &mut Output<'_>, entries: &[ArchiveEntry]) -> io::Result<()> {
+    const ZIP64_LIMIT: u64 = 0xffff_ffff;
+    const FLAGS: u16 = 1 << 3 | 1 << 11; // data descriptor, UTF-8 names
+    let mut central = Vec::new();
+
+    for entry in entries {
+        let offset = output.written;
+        let zip64 = entry.size >= ZIP64_LIMIT;
+        let version = if zip64 { 45 } else { 20 };
+        let (time, date) = dos_date_time(entry.modified);
+        let name = entry.name.as_bytes();
+
+        let mut header = Vec::with_capacity(30 + name.len() + 20);
+        put_u32(&mut header, 0x0403_4b50);
+        put_u16(&mut header, version);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, 0); // stored
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        put_u32(&mut header, 0); // CRC, in the data descriptor
+        let local_size = if zip64 { ZIP64_LIMIT as u32 } else { 0 };
+        put_u32(&mut header, local_size);
+        put_u32(&mut header, local_size);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, if zip64 { 20 } else { 0 });
+        header.extend_from_slice(name);
+        if zip64 {
+            put_u16(&mut header, 0x0001);
+            put_u16(&mut header, 16);
+            put_u64(&mut header, 0);
+            put_u64(&mut header, 0);
+        }
+        output.write(&header).await?;
+
+        let mut crc = Crc::new();
+        if !entry.is_dir {
+            output
+                .copy_file(&entry.path, entry.size, Some(&mut crc))
+                .await?;
+        }
+
+        let mut descriptor = Vec::with_capacity(24);
+        put_u32(&mut descriptor, 0x0807_4b50);
+        put_u32(&mut descriptor, crc.sum());
+        if zip64 {
+            put_u64(&mut descriptor, entry.size);
+            put_u64(&mut descriptor, entry.size);
+        } else {
+            put_u32(&mut descriptor, entry.size as u32);
+            put_u32(&mut descriptor, entry.size as u32);
+        }
+        output.write(&descriptor).await?;
+
+        // Only the fields that overflow go into the ZIP64 extra field
+        let mut extra = Vec::new();
+        if zip64 {
+            put_u64(&mut extra, entry.size);
+            put_u64(&mut extra, entry.size);
+        }
+        if offset >= ZIP64_LIMIT {
+            put_u64(&mut extra, offset);
+        }
+        let version = if extra.is_empty() { 20 } else { 45 };
+        let mode: u32 = if entry.is_dir { 0o40755 } else { 0o100644 };
+        let size = entry.size.min(ZIP64_LIMIT) as u32;
+        put_u32(&mut central, 0x0201_4b50);
+        put_u16(&mut central, 3 << 8 | version); // made by Unix
+        put_u16(&mut central, version);
+        put_u16(&mut central, FLAGS);
+        put_u16(&mut central, 0);
+        put_u16(&mut central, time);
+        put_u16(&mut central, date);
+        put_u32(&mut central, crc.sum());
+        put_u32(&mut central, size);
+        put_u32(&mut central, size);
+        put_u16(&mut central, name.len() as u16);
+        put_u16(
+            &mut central,
+            if extra.is_empty() {
+                0
+            } else {
+                4 + extra.len() as u16
+            },
+        );
+        put_u16(&mut central, 0); // comment
+        put_u16(&mut central, 0); // disk
+        put_u16(&mut central, 0); // internal attributes
+        put_u32(
+            &mut central,
+            mode << 16 | if entry.is_dir { 0x10 } else { 0 },
+        );
+        put_u32(&mut central, offset.min(ZIP64_LIMIT) as u32);
+        central.extend_from_slice(name);
+        if !extra.is_empty() {
+            put_u16(&mut central, 0x0001);
+            put_u16(&mut central, extra.len() as u16);
+            central.extend_from_slice(&extra);
+        }
+    }
+
+    let central_offset = output.written;
+    let central_size = central.len() as u64;
+    let count = entries.len() as u64;
+    output.write(&central).await?;
+
+    let mut end = Vec::with_capacity(98);
+    if count >= 0xffff || central_size >= ZIP64_LIMIT || central_offset >= ZIP64_LIMIT {
+        let zip64_end = output.written;
+        put_u32(&mut end, 0x0606_4b50);
+        put_u64(&mut end, 44);
+        put_u16(&mut end, 3 << 8 | 45);
+        put_u16(&mut end, 45);
+        put_u32(&mut end, 0);
+        put_u32(&mut end, 0);
+        put_u64(&mut end, count);
+        put_u64(&mut end, count);
+        put_u64(&mut end, central_size);
+        put_u64(&mut end, central_offset);
+        put_u32(&mut end, 0x0706_4b50);
+        put_u32(&mut end, 0);
+        put_u64(&mut end, zip64_end);
+        put_u32(&mut end, 1);
+    }
+    put_u32(&mut end, 0x0605_4b50);
+    put_u16(&mut end, 0);
+    put_u16(&mut end, 0);
+    put_u16(&mut end, count.min(0xffff) as u16);
+    put_u16(&mut end, count.min(0xffff) as u16);
+    put_u32(&mut end, central_size.min(ZIP64_LIMIT) as u32);
+    put_u32(&mut end, central_offset.min(ZIP64_LIMIT) as u32);
+    put_u16(&mut end, 0);
+    output.write(&end).await
+}
+
+// ustar headers, with a pax header in front for names or sizes that don't fit
+async fn write_tar(output: &mut Output<'_>, entries: &[ArchiveEntry]) -> io::Result<()> {
+    for entry in entries {
+        let mtime = entry
+            .modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (prefix, name) = split_ustar_name(&entry.name);
+        let size_fits = entry.size < 0o777_7777_7777;
+        if name.is_none() || !size_fits {
+            let mut records = String::new();
+            if name.is_none() {
+                records.push_str(&pax_record("path", &entry.name));
+            }
+            if !size_fits {
+                records.push_str(&pax_record("size", &entry.size.to_string()));
+            }
+            let pax_name = format!("PaxHeader/{}", truncate(&entry.name, 80));
+            let header = tar_header("", &pax_name, b'x', records.len() as u64, mtime, 0o644);
+            output.write(&header).await?;
+            output.write(records.as_bytes()).await?;
+            output.write(&padding(records.len() as u64)).await?;
+        }
+
+        let (prefix, name) = match name {
+            Some(name) => (prefix, name),
+            None => ("", truncate(&entry.name, 100)),
+        };
+        let (kind, mode) = if entry.is_dir {
+            (b'5', 0o755)
+        } else {
+            (b'0', 0o644)
+        };
+        let size = if size_fits { entry.size } else { 0 };
+        output
+            .write(&tar_header(prefix, name, kind, size, mtime, mode))
+            .await?;
+        if !entry.is_dir {
+            output.copy_file(&entry.path, entry.size, None).await?;
+            output.write(&padding(entry.size)).await?;
+        }
+    }
+    output.write(&[0; 1024]).await
+}
+
+fn tar_header(prefix: &str, name: &str, kind: u8, size: u64, mtime: u64, mode: u32) -> [u8; 512] {
+    let mut header = [0u8; 512];
+    let mut put = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    put(0, name.as_bytes());
+    put(100, format!("{mode:07o}\0").as_bytes());
+    put(108, b"0000000\0");
+    put(116, b"0000000\0");
+    put(124, format!("{size:011o}\0").as_bytes());
+    put(
+        136,
+        format!("{:011o}\0", mtime.min(0o777_7777_7777)).as_bytes(),
+    );
+    put(148, b"        ");
+    put(156, &[kind]);
+    put(257, b"ustar\0");
+    put(263, b"00");
+    put(345, prefix.as_bytes());
+    let checksum: u32 = header.iter().map(|byte| *byte as u32).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    header
+}
+
+// Split a name into the ustar `prefix` (155 bytes) and `name` (100 bytes)
+// fields at a `/`, or `None` when it cannot be done
+fn split_ustar_name(name: &str) -> (&str, Option<&str>) {
+    if name.len() <= 100 {
+        return ("", Some(name));
+    }
+    let trimmed = name.trim_end_matches('/');
+    for (i, _) in trimmed.match_indices('/') {
+        let (prefix, rest) = (&name[..i], &name[i + 1..]);
+        if prefix.len() <= 155 && rest.len() <= 100 && !rest.is_empty() {
+            return (prefix, Some(rest));
+        }
+    }
+    ("", None)
+}
+
+// `"<len> <key>=<value>\n"`, where the length counts its own digits
+fn pax_record(key: &str, value: &str) -> String {
+    let base = key.len() + value.len() + 3;
+    let mut len = base + base.to_string().len();
+    if len.to_string().len() + base != len {
+        len = base + len.to_string().len();
+    }
+    format!("{len} {key}={value}\n")
+}
+
+fn padding(size: u64) -> Vec<u8> {
+    vec![0; ((512 - size % 512) % 512) as usize]
+}
+
+fn truncate(text: &str, max: usize) -> &str {
+    let mut end = text.len().min(max);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+// MS-DOS time and date fields, which start in 1980
+fn dos_date_time(time: SystemTime) -> (u16, u16) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    if year < 1980 {
+        return (0, 1 << 5 | 1);
+    }
+    let rem = secs % 86400;
+    let time = (rem / 3600) << 11 | (rem % 3600 / 60) << 5 | (rem % 60 / 2);
+    let date = ((year - 1980).min(127) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        io::Read,
+        path::{Path, PathBuf},
+    };
+
+    use flate2::{read::GzDecoder, Crc};
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::config::Access;
+
+    use super::{collect, pax_record, split_ustar_name, write_archive, ArchiveFormat};
+
+    fn fixture(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("minginx-{name}-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub/.git")).unwrap();
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        fs::write(dir.join("sub/b.txt"), "world!").unwrap();
+        fs::write(dir.join("sub/.git/config"), "secret").unwrap();
+        dir
+    }
+
+    // Body of an archive response, with the chunked framing removed
+    async fn archive(format: ArchiveFormat, dir: &Path) -> Vec<u8> {
+        let entries = collect(dir, "/files/", &Access::default()).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let writer = tokio::spawn(async move {
+            write_archive(&mut server, format, &entries, 6)
+                .await
+                .unwrap();
+        });
+        let mut chunked = Vec::new();
+        client.read_to_end(&mut chunked).await.unwrap();
+        writer.await.unwrap();
+
+        let mut body = Vec::new();
+        let mut rest = &chunked[..];
+        loop {
+            let line_end = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size =
+                usize::from_str_radix(std::str::from_utf8(&rest[..line_end]).unwrap(), 16).unwrap();
+            if size == 0 {
+                return body;
+            }
+            body.extend_from_slice(&rest[line_end + 2..line_end + 2 + size]);
+            rest = &rest[line_end + 2 + size + 2..];
+        }
+    }
+
+    #[tokio::test]
+    async fn test_collect() {
+        let dir = fixture("collect");
+        let entries = collect(&dir, "/files/", &Access::default()).await.unwrap();
+        let names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(vec!["a.txt", "sub/", "sub/b.txt"], names);
+        assert_eq!(6, entries[2].size);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tar_gz() {
+        let dir = fixture("tar_gz");
+        let gz = archive(ArchiveFormat::TarGz, &dir).await;
+        let mut tar = Vec::new();
+        GzDecoder::new(&gz[..]).read_to_end(&mut tar).unwrap();
+
+        assert_eq!(512 * 7, tar.len());
+        assert_eq!(b"a.txt\0", &tar[..6]);
+        assert_eq!(b"ustar\0", &tar[257..263]);
+        assert_eq!(b"hello", &tar[512..517]);
+        assert_eq!(b"sub/\0", &tar[1024..1029]);
+        assert_eq!(b'5', tar[1024 + 156]);
+        assert_eq!(b"world!", &tar[2048..2054]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_zip() {
+        let dir = fixture("zip");
+        let zip = archive(ArchiveFormat::Zip, &dir).await;
+        let end = &zip[zip.len() - 22..];
+        assert_eq!(&0x0605_4b50u32.to_le_bytes(), &end[..4]);
+        assert_eq!(3, u16::from_le_bytes([end[10], end[11]]));
+
+        // The first entry: local header, data, then its data descriptor
+        assert_eq!(b"a.txt", &zip[30..35]);
+        assert_eq!(b"hello", &zip[35..40]);
+        let mut crc = Crc::new();
+        crc.update(b"hello");
+        assert_eq!(&crc.sum().to_le_bytes(), &zip[44..48]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tar_names() {
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(50));
+        assert_eq!(("", Some("a/b.txt")), split_ustar_name("a/b.txt"));
+        assert_eq!((&long[..120], Some(&long[121..])), split_ustar_name(&long));
+        assert_eq!(("", None), split_ustar_name(&"x".repeat(101)));
+        assert_eq!("12 path=abc\n", pax_record("path", "abc"));
+        let record = pax_record("path", &"y".repeat(95));
+        assert_eq!(record.len().to_string(), record.split(' ').next().unwrap());
+    }
+}
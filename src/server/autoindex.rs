@@ -0,0 +1,332 @@
+use std::{cmp::Ordering, io, path::Path, time::SystemTime};
+
+use serde::Deserialize;
+use tokio::fs;
+
+use crate::http::{
+    date::{fmt_http_date, fmt_listing_date},
+    escape::{html_escape, json_escape},
+    uri::percent_encode,
+};
+
+// Representation of a directory listing, as nginx's `autoindex_format`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Html,
+    Json,
+    Xml,
+}
+
+impl Format {
+    pub const ALL: [Format; 3] = [Format::Html, Format::Json, Format::Xml];
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Format::Html => "text/html",
+            Format::Json => "application/json",
+            Format::Xml => "application/xml",
+        }
+    }
+
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| format.mime() == mime)
+    }
+}
+
+// One file or directory shown in a listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    // Content type of files, filled in by the caller
+    pub mime: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+impl SortKey {
+    // `?sort=` value, unknown values sort by name
+    pub fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some("size") => SortKey::Size,
+            Some("mtime") | Some("time") | Some("date") => SortKey::Modified,
+            _ => SortKey::Name,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Modified => "mtime",
+        }
+    }
+}
+
+impl Order {
+    pub fn parse(value: Option<&str>) -> Self {
+        match value {
+            Some("desc") => Order::Desc,
+            _ => Order::Asc,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Order::Asc => "asc",
+            Order::Desc => "desc",
+        }
+    }
+}
+
+// Entries of `dir`; symlinks are described by their target when it exists
+pub async fn read_entries(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut dir_entries = fs::read_dir(dir).await?;
+    while let Some(entry) = dir_entries.next_entry().await? {
+        let metadata = match fs::metadata(entry.path()).await {
+            Ok(metadata) => metadata,
+            Err(_) => entry.metadata().await?,
+        };
+        entries.push(Entry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+            mime: None,
+        });
+    }
+    Ok(entries)
+}
+
+// Directories first, then by `key`; ties fall back to the name
+pub fn sort_entries(entries: &mut [Entry], key: SortKey, order: Order) {
+    entries.sort_by(|a, b| {
+        let by_key = match key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+        .then_with(|| a.name.cmp(&b.name));
+        let by_key = match order {
+            Order::Asc => by_key,
+            Order::Desc => by_key.reverse(),
+        };
+        b.is_dir.cmp(&a.is_dir).then(by_key)
+    });
+}
+
+// `1536` -> `1.5 KiB`
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    if size < 1024 {
+        return format!("{size} B");
+    }
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+const STYLE: &str = "body{font-family:system-ui,sans-serif;margin:2em;color:#222}\
+h1{font-size:1.4em;font-weight:normal;word-break:break-all}\
+table{border-collapse:collapse;width:100%}\
+th,td{padding:.3em .8em;text-align:left;white-space:nowrap}\
+th{border-bottom:1px solid #ccc}th a{color:inherit}\
+td.size,th.size{text-align:right}\
+tr:nth-child(even){background:#f6f6f6}\
+a{color:#0645ad;text-decoration:none}a:hover{text-decoration:underline}";
+
+// Render the HTML index of the directory at `uri_path`
+pub fn render_html(uri_path: &str, entries: &[Entry], key: SortKey, order: Order) -> String {
+    let title = html_escape(uri_path);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Index of {title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n\
+         <h1>Index of {title}</h1>\n<table>\n<tr>"
+    );
+    for (column, label, class) in [
+        (SortKey::Name, "Name", "name"),
+        (SortKey::Size, "Size", "size"),
+        (SortKey::Modified, "Modified", "mtime"),
+    ] {
+        // Clicking the current column flips the order
+        let next = if column == key && order == Order::Asc {
+            Order::Desc
+        } else {
+            Order::Asc
+        };
+        let arrow = match (column == key, order) {
+            (false, _) => "",
+            (true, Order::Asc) => " &#9650;",
+            (true, Order::Desc) => " &#9660;",
+        };
+        html.push_str(&format!(
+            "<th class=\"{class}\"><a href=\"?sort={}&amp;order={}\">{label}{arrow}</a></th>",
+            column.as_str(),
+            next.as_str()
+        ));
+    }
+    html.push_str("</tr>\n");
+
+    if uri_path != "/" {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "<td class=\"size\">-</td>".to_string()
+        } else {
+            format!(
+                "<td class=\"size\" title=\"{} bytes\">{}</td>",
+                entry.size,
+                human_size(entry.size)
+            )
+        };
+        let modified = entry.modified.map(fmt_listing_date).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"./{}{suffix}\">{}{suffix}</a></td>{size}<td>{modified}</td></tr>\n",
+            percent_encode(&entry.name),
+            html_escape(&entry.name),
+        ));
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+// `[{"name":"a.txt","type":"file","mtime":"...","size":5,"mime":"text/plain"}, ...]`
+pub fn render_json(entries: &[Entry]) -> String {
+    let items: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let mut item = format!(
+                "{{\"name\":\"{}\",\"type\":\"{}\"",
+                json_escape(&entry.name),
+                if entry.is_dir { "directory" } else { "file" }
+            );
+            if let Some(modified) = entry.modified {
+                item.push_str(&format!(",\"mtime\":\"{}\"", fmt_http_date(modified)));
+            }
+            if !entry.is_dir {
+                item.push_str(&format!(",\"size\":{}", entry.size));
+            }
+            if let Some(mime) = &entry.mime {
+                item.push_str(&format!(",\"mime\":\"{}\"", json_escape(mime)));
+            }
+            item.push('}');
+            item
+        })
+        .collect();
+    format!("[\n{}\n]\n", items.join(",\n"))
+}
+
+// `<list><directory mtime="...">img</directory><file ...>a.txt</file></list>`
+pub fn render_xml(entries: &[Entry]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<list>\n");
+    for entry in entries {
+        let tag = if entry.is_dir { "directory" } else { "file" };
+        let mut attributes = String::new();
+        if let Some(modified) = entry.modified {
+            attributes.push_str(&format!(" mtime=\"{}\"", fmt_http_date(modified)));
+        }
+        if !entry.is_dir {
+            attributes.push_str(&format!(" size=\"{}\"", entry.size));
+        }
+        if let Some(mime) = &entry.mime {
+            attributes.push_str(&format!(" mime=\"{}\"", html_escape(mime)));
+        }
+        xml.push_str(&format!(
+            "<{tag}{attributes}>{}</{tag}>\n",
+            html_escape(&entry.name)
+        ));
+    }
+    xml.push_str("</list>\n");
+    xml
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{
+        human_size, render_html, render_json, render_xml, sort_entries, Entry, Order, SortKey,
+    };
+
+    fn entry(name: &str, is_dir: bool, size: u64, secs: u64) -> Entry {
+        Entry {
+            name: name.into(),
+            is_dir,
+            size,
+            modified: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+            mime: (!is_dir).then(|| "text/plain".into()),
+        }
+    }
+
+    #[test]
+    fn test_sort_entries() {
+        let mut entries = vec![
+            entry("b.txt", false, 10, 3),
+            entry("Z", true, 0, 1),
+            entry("a.txt", false, 30, 1),
+            entry("c", true, 0, 2),
+        ];
+        let names = |entries: &[Entry]| entries.iter().map(|e| e.name.clone()).collect::<Vec<_>>();
+
+        sort_entries(&mut entries, SortKey::Name, Order::Asc);
+        assert_eq!(vec!["c", "Z", "a.txt", "b.txt"], names(&entries));
+        sort_entries(&mut entries, SortKey::Size, Order::Desc);
+        assert_eq!(vec!["Z", "c", "a.txt", "b.txt"], names(&entries));
+        sort_entries(&mut entries, SortKey::Modified, Order::Desc);
+        assert_eq!(vec!["c", "Z", "b.txt", "a.txt"], names(&entries));
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!("0 B", human_size(0));
+        assert_eq!("1023 B", human_size(1023));
+        assert_eq!("1.5 KiB", human_size(1536));
+        assert_eq!("2.0 GiB", human_size(2 << 30));
+    }
+
+    #[test]
+    fn test_render_html() {
+        let entries = vec![entry("<a>&b #1.txt", false, 5, 0)];
+        let html = render_html("/x/", &entries, SortKey::Size, Order::Asc);
+        assert!(html.contains("href=\"./%3Ca%3E%26b%20%231.txt\">&lt;a&gt;&amp;b #1.txt</a>"));
+        assert!(html.contains("<a href=\"../\">"));
+        assert!(html.contains("?sort=size&amp;order=desc"));
+        assert!(!render_html("/", &[], SortKey::Name, Order::Asc).contains("../"));
+    }
+
+    #[test]
+    fn test_render_json_xml() {
+        let entries = vec![entry("img", true, 0, 0), entry("a\"<.txt", false, 5, 0)];
+        assert_eq!(
+            "[\n{\"name\":\"img\",\"type\":\"directory\",\"mtime\":\"Thu, 01 Jan 1970 00:00:00 GMT\"},\n\
+             {\"name\":\"a\\\"<.txt\",\"type\":\"file\",\"mtime\":\"Thu, 01 Jan 1970 00:00:00 GMT\",\
+             \"size\":5,\"mime\":\"text/plain\"}\n]\n",
+            render_json(&entries)
+        );
+        let xml = render_xml(&entries);
+        assert!(xml.contains("<directory mtime=\"Thu, 01 Jan 1970 00:00:00 GMT\">img</directory>"));
+        assert!(xml.contains(" size=\"5\" mime=\"text/plain\">a&quot;&lt;.txt</file>"));
+    }
+}
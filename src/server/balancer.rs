@@ -0,0 +1,507 @@
+use std::{
+    collections::hash_map::{DefaultHasher, RandomState},
+    hash::{BuildHasher, Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use crate::http::request::Request;
+
+use super::{
+    health::{Health, HealthCheckConfig},
+    upstream::Address,
+};
+
+// Points each unit of weight puts on the hash ring
+const RING_POINTS: usize = 160;
+
+// A named group of servers a `[[proxy]]` can `pass` requests to
+#[derive(Debug, Clone, Deserialize)]
+pub struct UpstreamConfig {
+    pub name: String,
+    #[serde(default)]
+    pub balance: Balance,
+    // What `hash` balancing keys on: `ip`, `header:<name>` or `cookie:<name>`
+    #[serde(default = "default_hash")]
+    pub hash: String,
+    #[serde(rename = "server")]
+    pub servers: Vec<ServerConfig>,
+    // Consecutive failed requests after which a server is left out for
+    // `fail_timeout` seconds, 0 to never leave it out
+    #[serde(default = "default_max_fails")]
+    pub max_fails: u32,
+    #[serde(default = "default_fail_timeout")]
+    pub fail_timeout: u64,
+    pub health_check: Option<HealthCheckConfig>,
+}
+
+fn default_hash() -> String {
+    "ip".into()
+}
+
+fn default_max_fails() -> u32 {
+    1
+}
+
+fn default_fail_timeout() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    // `127.0.0.1:8000` or `unix:/run/app.sock`
+    pub address: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    // Most requests in flight at once, 0 for no limit
+    #[serde(default)]
+    pub max_conns: usize,
+    // Only used while every other server is unavailable
+    #[serde(default)]
+    pub backup: bool,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+    // Each server in turn, ignoring weights
+    #[default]
+    RoundRobin,
+    // Smooth weighted round-robin, as nginx does
+    Weighted,
+    // Fewest requests in flight relative to weight
+    LeastConn,
+    // The less loaded of two servers picked at random
+    RandomTwo,
+    // Consistent hashing of the `hash` key, so a client sticks to a server
+    Hash,
+}
+
+// Runtime state of every configured upstream group
+#[derive(Default)]
+pub struct Upstreams {
+    groups: Vec<Group>,
+}
+
+impl Upstreams {
+    pub fn new(configs: &[UpstreamConfig]) -> Self {
+        Self {
+            groups: configs.iter().map(Group::new).collect(),
+        }
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|group| group.config.name == name)
+    }
+
+    pub fn groups(&self) -> &[Group] {
+        &self.groups
+    }
+
+    // State of every server by group, for the status page
+    pub fn stats(&self) -> Vec<(&str, Vec<ServerStats<'_>>)> {
+        let now = Instant::now();
+        self.groups
+            .iter()
+            .map(|group| {
+                let servers = (0..group.len())
+                    .map(|index| {
+                        let server = &group.config.servers[index];
+                        ServerStats {
+                            address: &server.address,
+                            weight: server.weight,
+                            backup: server.backup,
+                            state: group.health[index].state(now),
+                            active: group.active[index].load(Ordering::Relaxed),
+                            fails: group.health[index].fails(),
+                        }
+                    })
+                    .collect();
+                (group.name(), servers)
+            })
+            .collect()
+    }
+}
+
+pub struct ServerStats<'a> {
+    pub address: &'a str,
+    pub weight: u32,
+    pub backup: bool,
+    pub state: &'static str,
+    pub active: usize,
+    pub fails: u32,
+}
+
+pub struct Group {
+    config: UpstreamConfig,
+    addresses: Vec<Address>,
+    // Requests in flight per server
+    active: Vec<AtomicUsize>,
+    health: Vec<Health>,
+    next: AtomicUsize,
+    current_weights: Mutex<Vec<i64>>,
+    random: AtomicU64,
+    // Sorted points of the consistent hash ring and their servers
+    ring: Vec<(u64, usize)>,
+}
+
+// A server chosen for a request, counted as busy until dropped
+pub struct Peer<'a> {
+    group: &'a Group,
+    pub index: usize,
+}
+
+impl Peer<'_> {
+    // The server's address as configured
+    pub fn name(&self) -> &str {
+        self.group.server_name(self.index)
+    }
+
+    pub fn succeeded(&self) {
+        self.group.health[self.index].succeeded();
+    }
+
+    // Count a failed request towards the group's `max_fails`
+    pub fn failed(&self) {
+        let config = &self.group.config;
+        let fail_timeout = Duration::from_secs(config.fail_timeout);
+        let health = &self.group.health[self.index];
+        if health.failed(Instant::now(), config.max_fails, fail_timeout) {
+            log::warn!(
+                "{} in {} is down for {}s after {} failed requests",
+                self.name(),
+                config.name,
+                config.fail_timeout,
+                health.fails()
+            );
+        }
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.group.addresses[self.index]
+    }
+}
+
+impl Drop for Peer<'_> {
+    fn drop(&mut self) {
+        self.group.active[self.index].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Group {
+    fn new(config: &UpstreamConfig) -> Self {
+        let mut ring = Vec::new();
+        for (index, server) in config.servers.iter().enumerate() {
+            for point in 0..RING_POINTS * server.weight as usize {
+                ring.push((hash(&(&server.address, point)), index));
+            }
+        }
+        ring.sort_unstable();
+        Self {
+            addresses: config
+                .servers
+                .iter()
+                .map(|s| Address::parse(&s.address))
+                .collect(),
+            active: config.servers.iter().map(|_| AtomicUsize::new(0)).collect(),
+            health: config.servers.iter().map(|_| Health::default()).collect(),
+            next: AtomicUsize::new(0),
+            current_weights: Mutex::new(vec![0; config.servers.len()]),
+            random: AtomicU64::new(RandomState::new().build_hasher().finish() | 1),
+            ring,
+            config: config.clone(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.config.servers.len()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn server_name(&self, index: usize) -> &str {
+        &self.config.servers[index].address
+    }
+
+    pub fn addresses(&self) -> &[Address] {
+        &self.addresses
+    }
+
+    pub fn health(&self, index: usize) -> &Health {
+        &self.health[index]
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheckConfig> {
+        self.config.health_check.as_ref()
+    }
+
+    // The value `hash` balancing keys on for `req`, `None` when it is absent
+    pub fn hash_key(&self, req: &Request, client: Option<IpAddr>) -> Option<String> {
+        let key = &self.config.hash;
+        if let Some(name) = key.strip_prefix("header:") {
+            req.header(name).map(str::to_string)
+        } else if let Some(name) = key.strip_prefix("cookie:") {
+            req.cookie(name).map(str::to_string)
+        } else {
+            client.map(|ip| ip.to_string())
+        }
+    }
+
+    // Choose a server for a request, skipping those in `tried`, those down
+    // and those at their `max_conns`. Backup servers are only chosen when
+    // no other is left.
+    pub fn pick(&self, key: Option<&str>, tried: &[usize]) -> Option<Peer<'_>> {
+        let mut skipped = tried.to_vec();
+        let now = Instant::now();
+        loop {
+            let available = |backup: bool| -> Vec<usize> {
+                (0..self.len())
+                    .filter(|index| self.config.servers[*index].backup == backup)
+                    .filter(|index| !skipped.contains(index))
+                    .filter(|index| self.health[*index].is_up(now))
+                    .filter(|index| self.has_room(*index))
+                    .collect()
+            };
+            let mut candidates = available(false);
+            if candidates.is_empty() {
+                candidates = available(true);
+            }
+            if candidates.is_empty() {
+                return None;
+            }
+            let index = self.choose(&candidates, key);
+            // Another request may have taken the last slot meanwhile
+            if self.acquire(index) {
+                return Some(Peer { group: self, index });
+            }
+            skipped.push(index);
+        }
+    }
+
+    fn choose(&self, candidates: &[usize], key: Option<&str>) -> usize {
+        let servers = &self.config.servers;
+        // Compare `active / weight` without dividing
+        let load = |index: usize| {
+            let active = self.active[index].load(Ordering::Relaxed) as u64;
+            (active, servers[index].weight.max(1) as u64)
+        };
+        let less_loaded = |a: usize, b: usize| {
+            let ((active_a, weight_a), (active_b, weight_b)) = (load(a), load(b));
+            active_a * weight_b <= active_b * weight_a
+        };
+        match (self.config.balance, key) {
+            (Balance::Weighted, _) => {
+                let mut current = self.current_weights.lock().unwrap();
+                let total: i64 = candidates.iter().map(|&i| servers[i].weight as i64).sum();
+                let mut best = candidates[0];
+                for &index in candidates {
+                    current[index] += servers[index].weight as i64;
+                    if current[index] > current[best] {
+                        best = index;
+                    }
+                }
+                current[best] -= total;
+                best
+            }
+            (Balance::LeastConn, _) => {
+                // Start at a rotating offset so ties are spread out
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..candidates.len())
+                    .map(|offset| candidates[(start + offset) % candidates.len()])
+                    .reduce(|best, index| {
+                        if less_loaded(best, index) {
+                            best
+                        } else {
+                            index
+                        }
+                    })
+                    .unwrap_or(candidates[0])
+            }
+            (Balance::RandomTwo, _) if candidates.len() > 1 => {
+                let first = self.random() as usize % candidates.len();
+                let second = (first + 1 + self.random() as usize % (candidates.len() - 1))
+                    % candidates.len();
+                let (a, b) = (candidates[first], candidates[second]);
+                if less_loaded(a, b) {
+                    a
+                } else {
+                    b
+                }
+            }
+            (Balance::Hash, Some(key)) => {
+                // The first point at or after the key's hash whose server
+                // can take the request, wrapping around the ring
+                let start = self.ring.partition_point(|(point, _)| *point < hash(&key));
+                (0..self.ring.len())
+                    .map(|offset| self.ring[(start + offset) % self.ring.len()].1)
+                    .find(|index| candidates.contains(index))
+                    .unwrap_or(candidates[0])
+            }
+            _ => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                candidates[next % candidates.len()]
+            }
+        }
+    }
+
+    fn has_room(&self, index: usize) -> bool {
+        let max_conns = self.config.servers[index].max_conns;
+        max_conns == 0 || self.active[index].load(Ordering::Relaxed) < max_conns
+    }
+
+    fn acquire(&self, index: usize) -> bool {
+        let max_conns = self.config.servers[index].max_conns;
+        self.active[index]
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                (max_conns == 0 || active < max_conns).then_some(active + 1)
+            })
+            .is_ok()
+    }
+
+    // xorshift64
+    fn random(&self) -> u64 {
+        let mut x = self.random.load(Ordering::Relaxed);
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.random.store(x, Ordering::Relaxed);
+        x
+    }
+}
+
+// A hash that stays the same across runs, so the ring does too
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Balance, ServerConfig, UpstreamConfig, Upstreams};
+
+    fn new_upstreams(balance: Balance, servers: &[(u32, usize, bool)]) -> Upstreams {
+        let servers = servers
+            .iter()
+            .enumerate()
+            .map(|(i, &(weight, max_conns, backup))| ServerConfig {
+                address: format!("127.0.0.1:{}", 8000 + i),
+                weight,
+                max_conns,
+                backup,
+            })
+            .collect();
+        Upstreams::new(&[UpstreamConfig {
+            name: "app".into(),
+            balance,
+            hash: "ip".into(),
+            servers,
+            max_fails: 2,
+            fail_timeout: 10,
+            health_check: None,
+        }])
+    }
+
+    // Indices of `count` picks, each released before the next
+    fn picks(upstreams: &Upstreams, count: usize) -> Vec<usize> {
+        let group = upstreams.group("app").unwrap();
+        (0..count)
+            .map(|_| group.pick(None, &[]).unwrap().index)
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let upstreams = new_upstreams(Balance::RoundRobin, &[(5, 0, false), (1, 0, false)]);
+        assert_eq!(vec![0, 1, 0, 1], picks(&upstreams, 4));
+        let upstreams = new_upstreams(
+            Balance::Weighted,
+            &[(5, 0, false), (1, 0, false), (1, 0, false)],
+        );
+        assert_eq!(vec![0, 0, 1, 0, 2, 0, 0], picks(&upstreams, 7));
+    }
+
+    #[test]
+    fn test_least_conn_and_limits() {
+        let upstreams = new_upstreams(
+            Balance::LeastConn,
+            &[(1, 0, false), (2, 2, false), (1, 0, true)],
+        );
+        let group = upstreams.group("app").unwrap();
+        let held: Vec<_> = (0..3).map(|_| group.pick(None, &[]).unwrap()).collect();
+        let mut indices: Vec<_> = held.iter().map(|peer| peer.index).collect();
+        indices.sort();
+        // The heavier server takes two, then reaches its `max_conns`
+        assert_eq!(vec![0, 1, 1], indices);
+        assert_eq!(0, group.pick(None, &[]).unwrap().index);
+        // The backup only steps in once the others are out
+        assert_eq!(2, group.pick(None, &[0]).unwrap().index);
+        drop(held);
+        assert_eq!(1, group.pick(None, &[0]).unwrap().index);
+        assert!(group.pick(None, &[0, 1, 2]).is_none());
+    }
+
+    #[test]
+    fn test_failed_servers() {
+        let upstreams = new_upstreams(Balance::RoundRobin, &[(1, 0, false), (1, 0, true)]);
+        let group = upstreams.group("app").unwrap();
+        for _ in 0..2 {
+            group.pick(None, &[]).unwrap().failed();
+        }
+        // Down after `max_fails`, so the backup takes over
+        assert_eq!(1, group.pick(None, &[]).unwrap().index);
+        let stats = upstreams.stats();
+        assert_eq!("app", stats[0].0);
+        assert_eq!("down", stats[0].1[0].state);
+        assert_eq!(2, stats[0].1[0].fails);
+        assert_eq!("up", stats[0].1[1].state);
+    }
+
+    #[test]
+    fn test_random_two() {
+        let upstreams = new_upstreams(Balance::RandomTwo, &[(1, 0, false), (1, 0, false)]);
+        let group = upstreams.group("app").unwrap();
+        let busy = group.pick(None, &[]).unwrap();
+        // With two servers both are compared, so the idle one always wins
+        for _ in 0..10 {
+            assert_ne!(busy.index, group.pick(None, &[]).unwrap().index);
+        }
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let upstreams = new_upstreams(
+            Balance::Hash,
+            &[(1, 0, false), (1, 0, false), (1, 0, false)],
+        );
+        let group = upstreams.group("app").unwrap();
+        let keys: Vec<String> = (0..100).map(|i| format!("10.0.0.{i}")).collect();
+        let chosen: Vec<usize> = keys
+            .iter()
+            .map(|key| group.pick(Some(key), &[]).unwrap().index)
+            .collect();
+        for (key, &index) in keys.iter().zip(&chosen) {
+            assert_eq!(index, group.pick(Some(key), &[]).unwrap().index);
+        }
+        assert!((0..3).all(|index| chosen.contains(&index)));
+        // Without server 0 only its keys move
+        for (key, &index) in keys.iter().zip(&chosen) {
+            let moved = group.pick(Some(key), &[0]).unwrap().index;
+            if index != 0 {
+                assert_eq!(index, moved);
+            }
+        }
+    }
+}
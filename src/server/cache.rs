@@ -0,0 +1,559 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::Deserialize;
+use tokio::{fs, io::AsyncReadExt};
+
+// Bounded in-memory cache of static file contents
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FileCacheConfig {
+    pub enabled: bool,
+    pub max_entries: usize,
+    // Total size of cached contents in bytes
+    pub max_bytes: u64,
+    // Larger files are always read from disk
+    pub max_file_size: u64,
+    pub revalidate: Revalidate,
+}
+
+impl Default for FileCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_entries: 1024,
+            max_bytes: 64 * 1024 * 1024,
+            max_file_size: 1024 * 1024,
+            revalidate: Revalidate::Mtime,
+        }
+    }
+}
+
+// How cached entries notice that the file changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Revalidate {
+    // Compare size and modification time with a `stat` on every hit
+    Mtime,
+    // Drop entries on inotify events (Linux), without touching the disk on hits
+    Watch,
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedFile {
+    pub content: Arc<Vec<u8>>,
+    pub metadata: Metadata,
+}
+
+// Where the bytes of an opened file come from
+#[derive(Debug)]
+pub enum FileBody {
+    Memory(Arc<Vec<u8>>),
+    // Large files are copied straight from the descriptor to the socket
+    Stream(std::fs::File),
+}
+
+#[derive(Debug)]
+pub struct OpenedFile {
+    pub body: FileBody,
+    pub metadata: Metadata,
+}
+
+impl From<CachedFile> for OpenedFile {
+    fn from(file: CachedFile) -> Self {
+        Self {
+            body: FileBody::Memory(file.content),
+            metadata: file.metadata,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+// Cheap handle to the shared cache, a disabled cache reads straight from disk
+#[derive(Clone, Default)]
+pub struct FileCache {
+    inner: Option<Arc<Mutex<Lru>>>,
+    config: Option<Arc<FileCacheConfig>>,
+    #[cfg(target_os = "linux")]
+    watcher: Option<Arc<watch::Watcher>>,
+}
+
+impl FileCache {
+    pub fn new(config: &FileCacheConfig) -> Self {
+        if !config.enabled {
+            return Self::default();
+        }
+        let inner = Arc::new(Mutex::new(Lru::new(config.max_entries, config.max_bytes)));
+
+        #[cfg(target_os = "linux")]
+        let watcher = match config.revalidate {
+            Revalidate::Watch => match watch::Watcher::spawn(Arc::clone(&inner)) {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    log::error!("Failed to start file watcher, using mtime checks: {}", e);
+                    None
+                }
+            },
+            Revalidate::Mtime => None,
+        };
+
+        Self {
+            inner: Some(inner),
+            config: Some(Arc::new(config.clone())),
+            #[cfg(target_os = "linux")]
+            watcher,
+        }
+    }
+
+    // Whether hits can skip the `stat` call
+    fn watching(&self) -> bool {
+        #[cfg(target_os = "linux")]
+        return self.watcher.is_some();
+        #[cfg(not(target_os = "linux"))]
+        return false;
+    }
+
+    // Open `path` for sending. Files of at least `stream_min_size` bytes are
+    // left on disk to be streamed, smaller ones come from memory when fresh.
+    pub async fn open(&self, path: &Path, stream_min_size: u64) -> io::Result<OpenedFile> {
+        let (inner, config) = match (&self.inner, &self.config) {
+            (Some(inner), Some(config)) => (inner, config),
+            _ => return open_uncached(path, stream_min_size).await,
+        };
+
+        if self.watching() {
+            if let Some(file) = inner.lock().unwrap().get(path, None) {
+                return Ok(file.into());
+            }
+        }
+        let metadata = fs::metadata(path).await?;
+        if metadata.len() >= stream_min_size {
+            return open_stream(path).await;
+        }
+        if !self.watching() {
+            if let Some(file) = inner.lock().unwrap().get(path, Some(&metadata)) {
+                return Ok(file.into());
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(watcher) = &self.watcher {
+            // Watch before reading so a write racing with the read is not missed
+            if let Err(e) = watcher.watch(path) {
+                log::error!("Failed to watch {}: {}", path.display(), e);
+                return Ok(read_file(path).await?.into());
+            }
+        }
+
+        let file = read_file(path).await?;
+        if file.metadata.len() <= config.max_file_size {
+            inner
+                .lock()
+                .unwrap()
+                .insert(path.to_path_buf(), file.clone());
+        }
+        Ok(file.into())
+    }
+
+    pub fn stats(&self) -> Option<CacheStats> {
+        self.inner.as_ref().map(|inner| inner.lock().unwrap().stats)
+    }
+}
+
+async fn open_uncached(path: &Path, stream_min_size: u64) -> io::Result<OpenedFile> {
+    if fs::metadata(path).await?.len() >= stream_min_size {
+        open_stream(path).await
+    } else {
+        Ok(read_file(path).await?.into())
+    }
+}
+
+async fn open_stream(path: &Path) -> io::Result<OpenedFile> {
+    let file = fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    Ok(OpenedFile {
+        body: FileBody::Stream(file.into_std().await),
+        metadata,
+    })
+}
+
+async fn read_file(path: &Path) -> io::Result<CachedFile> {
+    let mut file = fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    let mut content = Vec::with_capacity(metadata.len() as usize);
+    file.read_to_end(&mut content).await?;
+    Ok(CachedFile {
+        content: Arc::new(content),
+        metadata,
+    })
+}
+
+struct Entry {
+    file: CachedFile,
+    last_used: u64,
+}
+
+// Least recently used eviction, ordered by a use counter
+struct Lru {
+    entries: HashMap<PathBuf, Entry>,
+    order: BTreeMap<u64, PathBuf>,
+    tick: u64,
+    max_entries: usize,
+    max_bytes: u64,
+    stats: CacheStats,
+}
+
+impl Lru {
+    fn new(max_entries: usize, max_bytes: u64) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            max_entries,
+            max_bytes,
+            stats: CacheStats::default(),
+        }
+    }
+
+    // A hit must still match `metadata` when one is given
+    fn get(&mut self, path: &Path, metadata: Option<&Metadata>) -> Option<CachedFile> {
+        let fresh = match (self.entries.get(path), metadata) {
+            (Some(entry), Some(metadata)) => {
+                entry.file.metadata.len() == metadata.len()
+                    && entry.file.metadata.modified().ok() == metadata.modified().ok()
+            }
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if !fresh {
+            self.remove(path);
+            self.stats.misses += 1;
+            return None;
+        }
+
+        self.tick += 1;
+        let entry = self.entries.get_mut(path)?;
+        self.order.remove(&entry.last_used);
+        entry.last_used = self.tick;
+        self.order.insert(self.tick, path.to_path_buf());
+        self.stats.hits += 1;
+        Some(entry.file.clone())
+    }
+
+    fn insert(&mut self, path: PathBuf, file: CachedFile) {
+        let size = file.content.len() as u64;
+        if size > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+        self.remove(&path);
+        while self.entries.len() >= self.max_entries || self.stats.bytes + size > self.max_bytes {
+            let oldest = match self.order.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, path.clone());
+        self.entries.insert(
+            path,
+            Entry {
+                file,
+                last_used: self.tick,
+            },
+        );
+        self.stats.entries = self.entries.len();
+        self.stats.bytes += size;
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.order.remove(&entry.last_used);
+            self.stats.entries = self.entries.len();
+            self.stats.bytes -= entry.file.content.len() as u64;
+        }
+    }
+
+    // Drop `path` and everything below it
+    fn remove_prefix(&mut self, path: &Path) {
+        let stale: Vec<PathBuf> = self
+            .entries
+            .keys()
+            .filter(|key| key.starts_with(path))
+            .cloned()
+            .collect();
+        for key in stale {
+            self.remove(&key);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod watch {
+    use std::{
+        collections::HashMap,
+        ffi::{CString, OsStr},
+        io,
+        os::unix::ffi::OsStrExt,
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::Lru;
+
+    const MASK: u32 = libc::IN_MODIFY
+        | libc::IN_ATTRIB
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_DELETE_SELF
+        | libc::IN_MOVE_SELF;
+
+    // inotify watches on the directories of cached files
+    pub struct Watcher {
+        fd: i32,
+        watches: Arc<Mutex<Watches>>,
+    }
+
+    #[derive(Default)]
+    struct Watches {
+        // Directory as used in cache keys (possibly empty) -> watch descriptor
+        dirs: HashMap<PathBuf, i32>,
+        wds: HashMap<i32, PathBuf>,
+    }
+
+    impl Watcher {
+        pub fn spawn(cache: Arc<Mutex<Lru>>) -> io::Result<Arc<Self>> {
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let watches = Arc::new(Mutex::new(Watches::default()));
+            let watcher = Arc::new(Self {
+                fd,
+                watches: Arc::clone(&watches),
+            });
+            thread::Builder::new()
+                .name("file-cache-watcher".into())
+                .spawn(move || read_events(fd, cache, watches))?;
+            Ok(watcher)
+        }
+
+        pub fn watch(&self, path: &Path) -> io::Result<()> {
+            let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            let mut watches = self.watches.lock().unwrap();
+            if watches.dirs.contains_key(&dir) {
+                return Ok(());
+            }
+            let target = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir.as_path()
+            };
+            let target = CString::new(target.as_os_str().as_bytes())?;
+            let wd = unsafe { libc::inotify_add_watch(self.fd, target.as_ptr(), MASK) };
+            if wd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            watches.dirs.insert(dir.clone(), wd);
+            watches.wds.insert(wd, dir);
+            Ok(())
+        }
+    }
+
+    fn read_events(fd: i32, cache: Arc<Mutex<Lru>>, watches: Arc<Mutex<Watches>>) {
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = unsafe { libc::read(fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+            if n < 0 {
+                if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                log::error!("File watcher stopped: {}", io::Error::last_os_error());
+                cache.lock().unwrap().remove_prefix(Path::new(""));
+                return;
+            }
+
+            let mut offset = 0;
+            while offset + header <= n as usize {
+                let event: libc::inotify_event =
+                    unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+                let name_bytes = &buffer[offset + header..offset + header + event.len as usize];
+                let name = OsStr::from_bytes(name_bytes.split(|b| *b == 0).next().unwrap_or(&[]));
+                offset += header + event.len as usize;
+
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    // Events were lost, nothing cached can be trusted
+                    cache.lock().unwrap().remove_prefix(Path::new(""));
+                    continue;
+                }
+                let dir = match watches.lock().unwrap().wds.get(&event.wd) {
+                    Some(dir) => dir.clone(),
+                    None => continue,
+                };
+                if event.mask & libc::IN_MOVE_SELF != 0 {
+                    // The path no longer leads to the directory
+                    unsafe { libc::inotify_rm_watch(fd, event.wd) };
+                }
+                if event.mask & libc::IN_IGNORED != 0 {
+                    // The watch is gone, after IN_DELETE_SELF or the removal
+                    // above. The next miss in the directory adds it again.
+                    let mut watches = watches.lock().unwrap();
+                    watches.wds.remove(&event.wd);
+                    if watches.dirs.get(&dir) == Some(&event.wd) {
+                        watches.dirs.remove(&dir);
+                    }
+                }
+                let mut cache = cache.lock().unwrap();
+                if name.is_empty() {
+                    cache.remove_prefix(&dir);
+                } else {
+                    cache.remove_prefix(&dir.join(name));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf, sync::Arc, time::Duration};
+
+    use super::{read_file, FileBody, FileCache, FileCacheConfig, Lru, Revalidate};
+
+    async fn cached(content: &[u8]) -> super::CachedFile {
+        let name = format!("minginx-lru-{}-{}", std::process::id(), content.len());
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, content).unwrap();
+        let file = read_file(&path).await.unwrap();
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    #[tokio::test]
+    async fn test_lru_eviction() {
+        let mut lru = Lru::new(2, 10);
+        lru.insert(PathBuf::from("a"), cached(b"aaa").await);
+        lru.insert(PathBuf::from("b"), cached(b"bbbb").await);
+        assert!(lru.get(&PathBuf::from("a"), None).is_some());
+
+        // Over the entry limit, `b` is the least recently used
+        lru.insert(PathBuf::from("c"), cached(b"c").await);
+        assert!(lru.get(&PathBuf::from("b"), None).is_none());
+        assert_eq!(2, lru.stats.entries);
+        assert_eq!(4, lru.stats.bytes);
+
+        // Over the byte limit, `a` goes as well
+        lru.insert(PathBuf::from("d"), cached(b"dddddddd").await);
+        assert!(lru.get(&PathBuf::from("a"), None).is_none());
+        assert_eq!(9, lru.stats.bytes);
+        assert_eq!(2, lru.stats.evictions);
+        assert_eq!(
+            Arc::new(b"dddddddd".to_vec()),
+            lru.get(&PathBuf::from("d"), None).unwrap().content
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mtime_revalidation() {
+        let name = format!("minginx-cache-{}.txt", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, "first").unwrap();
+        let cache = FileCache::new(&FileCacheConfig {
+            enabled: true,
+            ..FileCacheConfig::default()
+        });
+
+        let content = |file: super::OpenedFile| match file.body {
+            FileBody::Memory(content) => content.to_vec(),
+            FileBody::Stream(_) => panic!("small file streamed"),
+        };
+
+        assert_eq!(
+            b"first".to_vec(),
+            content(cache.open(&path, 1024).await.unwrap())
+        );
+        assert_eq!(
+            b"first".to_vec(),
+            content(cache.open(&path, 1024).await.unwrap())
+        );
+        let stats = cache.stats().unwrap();
+        assert_eq!((1, 1), (stats.hits, stats.misses));
+
+        std::thread::sleep(Duration::from_millis(10));
+        fs::write(&path, "second!").unwrap();
+        assert_eq!(
+            b"second!".to_vec(),
+            content(cache.open(&path, 1024).await.unwrap())
+        );
+        assert_eq!(2, cache.stats().unwrap().misses);
+
+        // Files over the streaming threshold bypass the cache
+        let opened = cache.open(&path, 4).await.unwrap();
+        assert!(matches!(opened.body, FileBody::Stream(_)));
+        assert_eq!(2, cache.stats().unwrap().misses);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_watch_recreated_dir() {
+        let dir = std::env::temp_dir().join(format!("minginx-watch-{}", std::process::id()));
+        let path = dir.join("a.txt");
+        let cache = FileCache::new(&FileCacheConfig {
+            enabled: true,
+            revalidate: Revalidate::Watch,
+            ..FileCacheConfig::default()
+        });
+        let content = |file: super::OpenedFile| match file.body {
+            FileBody::Memory(content) => content.to_vec(),
+            FileBody::Stream(_) => panic!("small file streamed"),
+        };
+        // Events arrive on the watcher thread a moment later
+        let settle = || std::thread::sleep(Duration::from_millis(100));
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "first").unwrap();
+        assert_eq!(
+            b"first".to_vec(),
+            content(cache.open(&path, 1024).await.unwrap())
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+        settle();
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&path, "second").unwrap();
+        assert_eq!(
+            b"second".to_vec(),
+            content(cache.open(&path, 1024).await.unwrap())
+        );
+
+        // The new directory is watched again
+        fs::write(&path, "third").unwrap();
+        settle();
+        assert_eq!(
+            b"third".to_vec(),
+            content(cache.open(&path, 1024).await.unwrap())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
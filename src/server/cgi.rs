@@ -0,0 +1,728 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use serde::Deserialize;
+use tokio::{
+    fs,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    process::{Child, Command},
+    time::{timeout_at, Instant},
+};
+
+use crate::{
+    config::Config,
+    http::{
+        request::Request,
+        response::{ContentType, HttpStatus},
+        uri::percent_decode,
+    },
+};
+
+// Largest header block accepted from a CGI program
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+// Programs run for requests, besides PHP. The limits apply to php-cgi too.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CgiConfig {
+    // URL prefixes whose files are executed, e.g. `/cgi-bin/`
+    pub dirs: Vec<String>,
+    // Interpreters by file extension, e.g. `py = "python3"`
+    pub interpreters: BTreeMap<String, PathBuf>,
+    // Extra environment variables for every script
+    pub env: BTreeMap<String, String>,
+    // Seconds a script may run before it is killed
+    pub timeout: u64,
+    // Script processes running at once, 0 for no limit
+    pub max_processes: usize,
+    // Seconds a request waits for a free process slot before a 503
+    pub queue_timeout: u64,
+    // Resource limits of each process: CPU seconds, address space bytes
+    // and open files
+    pub cpu_limit: Option<u64>,
+    pub memory_limit: Option<u64>,
+    pub open_files_limit: Option<u64>,
+}
+
+impl Default for CgiConfig {
+    fn default() -> Self {
+        Self {
+            dirs: Vec::new(),
+            interpreters: BTreeMap::new(),
+            env: BTreeMap::new(),
+            timeout: 60,
+            max_processes: 32,
+            queue_timeout: 10,
+            cpu_limit: None,
+            memory_limit: None,
+            open_files_limit: None,
+        }
+    }
+}
+
+impl CgiConfig {
+    // Whether the decoded request path `script_name` names a script
+    pub fn is_script(&self, script_name: &str) -> bool {
+        let in_dir = self.dirs.iter().any(|dir| {
+            let dir = dir.trim_end_matches('/');
+            script_name
+                .strip_prefix(dir)
+                .is_some_and(|rest| rest.len() > 1 && rest.starts_with('/'))
+        });
+        in_dir || self.interpreter(script_name).is_some()
+    }
+
+    // Interpreter for the script's extension, `None` for programs run directly
+    pub fn interpreter(&self, script_name: &str) -> Option<&Path> {
+        let name = script_name.rsplit('/').next()?;
+        let (_, extension) = name.rsplit_once('.')?;
+        self.interpreters.get(extension).map(PathBuf::as_path)
+    }
+
+    // Whether the request path leads to a script, possibly with a `PATH_INFO`
+    pub fn handles(&self, uri_path: &str) -> bool {
+        leads_to_script(uri_path, |name| self.is_script(name))
+    }
+}
+
+// Whether a leading part of the request path is a script name
+pub fn leads_to_script(uri_path: &str, is_script: impl Fn(&str) -> bool) -> bool {
+    let decoded = percent_decode(uri_path);
+    let found = script_ends(&decoded).any(|end| is_script(&decoded[..end]));
+    found
+}
+
+pub fn is_php_script(script_name: &str) -> bool {
+    script_name
+        .rsplit('/')
+        .next()
+        .is_some_and(|name| name.len() > 4 && name.ends_with(".php"))
+}
+
+// Byte offsets ending each leading segment of `uri_path`:
+// `/a/b.py/c` gives the ends of `/a`, `/a/b.py` and `/a/b.py/c`
+fn script_ends(uri_path: &str) -> impl Iterator<Item = usize> + '_ {
+    uri_path
+        .match_indices('/')
+        .map(|(i, _)| i)
+        .filter(|&i| i > 0)
+        .chain([uri_path.len()])
+}
+
+// A script found on disk for a request path
+#[derive(Debug, PartialEq, Eq)]
+pub struct FoundScript {
+    pub script_name: String,
+    pub path: PathBuf,
+    pub path_info: String,
+}
+
+// The first leading part of `uri_path` that `is_script` accepts and that
+// resolves to a file; the remainder becomes `PATH_INFO`
+pub async fn find_script(
+    config: &Config,
+    uri_path: &str,
+    is_script: impl Fn(&str) -> bool,
+) -> Option<FoundScript> {
+    for end in script_ends(uri_path) {
+        let script_name = percent_decode(&uri_path[..end]);
+        if !is_script(&script_name) {
+            continue;
+        }
+        let Some(path) = config.resolve(&uri_path[..end]) else {
+            continue;
+        };
+        if fs::metadata(&path)
+            .await
+            .is_ok_and(|metadata| metadata.is_file())
+        {
+            let script = script_at(config, &uri_path[..end], &path).await?;
+            return Some(FoundScript {
+                path_info: percent_decode(&uri_path[end..]),
+                ..script
+            });
+        }
+    }
+    None
+}
+
+// The script file `path` that `uri_path` resolved to. Scripts are run from
+// their own directory, so the path is made absolute; one leading outside the
+// document root through a symlink is refused.
+pub async fn script_at(config: &Config, uri_path: &str, path: &Path) -> Option<FoundScript> {
+    let path = fs::canonicalize(path).await.ok()?;
+    let root = fs::canonicalize(config.document_root(uri_path))
+        .await
+        .ok()?;
+    if !path.starts_with(&root) {
+        log::warn!(
+            "Refusing to run {}, outside of {}",
+            path.display(),
+            root.display()
+        );
+        return None;
+    }
+    Some(FoundScript {
+        script_name: percent_decode(uri_path),
+        path,
+        path_info: String::new(),
+    })
+}
+
+// The script a CGI request runs, as seen from the URL and on disk
+pub struct CgiScript<'a> {
+    // URL path of the script, e.g. `/blog/index.php`
+    pub script_name: &'a str,
+    pub script_filename: &'a Path,
+    // URL path following the script name, may be empty
+    pub path_info: &'a str,
+}
+
+// Meta-variables for a CGI/1.1 request (RFC 3875, section 4.1)
+pub fn cgi_env(
+    req: &Request,
+    script: &CgiScript,
+    config: &Config,
+    remote: Option<SocketAddr>,
+    local: Option<SocketAddr>,
+) -> Vec<(String, String)> {
+    let host = req.header("Host").unwrap_or_default();
+    // Strip the port, keeping IPv6 literals such as `[::1]:8080` intact
+    let server_name = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    let server_name = if server_name.is_empty() {
+        "localhost"
+    } else {
+        server_name
+    };
+    let server_port = local.map_or(config.port, |local| local.port());
+
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        (
+            "SERVER_SOFTWARE",
+            concat!("minginx/", env!("CARGO_PKG_VERSION")).to_string(),
+        ),
+        ("SERVER_PROTOCOL", req.version().to_string()),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", req.method().to_string()),
+        ("REQUEST_URI", req.path()),
+        ("QUERY_STRING", req.query().unwrap_or_default().to_string()),
+        ("SCRIPT_NAME", script.script_name.to_string()),
+        (
+            "SCRIPT_FILENAME",
+            script.script_filename.display().to_string(),
+        ),
+        ("PATH_INFO", script.path_info.to_string()),
+        ("DOCUMENT_ROOT", config.root.display().to_string()),
+        // Required by php-cgi built with `cgi.force_redirect`
+        ("REDIRECT_STATUS", "200".to_string()),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect::<Vec<_>>();
+
+    if !script.path_info.is_empty() {
+        if let Some(translated) = config.resolve(script.path_info) {
+            env.push(("PATH_TRANSLATED".into(), translated.display().to_string()));
+        }
+    }
+    if let Some(remote) = remote {
+        env.push(("REMOTE_ADDR".into(), remote.ip().to_string()));
+        env.push(("REMOTE_PORT".into(), remote.port().to_string()));
+    }
+    if let Some(content_type) = req.header("Content-Type") {
+        env.push(("CONTENT_TYPE".into(), content_type.to_string()));
+    }
+    if !req.body().is_empty() || req.header("Content-Length").is_some() {
+        env.push(("CONTENT_LENGTH".into(), req.body().len().to_string()));
+    }
+
+    for (name, value) in req.headers() {
+        // `Proxy` would become HTTP_PROXY, which CGI programs may take as
+        // their outgoing proxy (httpoxy)
+        if name.eq_ignore_ascii_case("Content-Type")
+            || name.eq_ignore_ascii_case("Content-Length")
+            || name.eq_ignore_ascii_case("Proxy")
+        {
+            continue;
+        }
+        let key = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        if let Some((_, existing)) = env.iter_mut().find(|(k, _)| *k == key) {
+            existing.push_str(", ");
+            existing.push_str(value);
+        } else {
+            env.push((key, value.clone()));
+        }
+    }
+    env
+}
+
+// Start `program` with `args`, only `env` (and PATH) set, in its own process
+// group under the configured resource limits. `body` is fed to its stdin and
+// its stderr logged in the background; the caller reads stdout.
+pub fn spawn(
+    program: &Path,
+    args: &[&Path],
+    env: Vec<(String, String)>,
+    dir: &Path,
+    body: Vec<u8>,
+    limits: &CgiConfig,
+) -> io::Result<Child> {
+    let mut command = Command::new(program);
+    command.args(args).env_clear().envs(env);
+    if let Some(path) = std::env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+    #[cfg(unix)]
+    {
+        let (cpu, memory, files) = (
+            limits.cpu_limit,
+            limits.memory_limit,
+            limits.open_files_limit,
+        );
+        // Only async-signal-safe calls between fork and exec
+        unsafe {
+            command.pre_exec(move || {
+                // A group of its own, so a timeout kills what it started too
+                if libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                set_rlimit(libc::RLIMIT_CPU, cpu)?;
+                set_rlimit(libc::RLIMIT_AS, memory)?;
+                set_rlimit(libc::RLIMIT_NOFILE, files)
+            });
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = limits;
+    let mut child = command
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    // A script may write output before reading all of its input, so stdin
+    // is written concurrently
+    if let Some(mut stdin) = child.stdin.take() {
+        tokio::spawn(async move {
+            let _ = stdin.write_all(&body).await;
+        });
+    }
+    if let Some(stderr) = child.stderr.take() {
+        let program = program.display().to_string();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                log::warn!("{}: {}", program, line);
+            }
+        });
+    }
+    Ok(child)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type Resource = libc::c_int;
+
+#[cfg(unix)]
+fn set_rlimit(resource: Resource, limit: Option<u64>) -> io::Result<()> {
+    let Some(limit) = limit else {
+        return Ok(());
+    };
+    let rlimit = libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &rlimit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Kill the script and anything it started
+pub fn kill_group(child: &Child) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = child;
+}
+
+// Wait for the script to exit, killing it once `deadline` has passed.
+// A script may close its output and keep running.
+pub async fn reap(child: &mut Child, deadline: Instant) {
+    if timeout_at(deadline, child.wait()).await.is_err() {
+        log::warn!("Script still running after its timeout, killing it");
+        kill_group(child);
+        let _ = child.wait().await;
+    }
+}
+
+// Read the header fields CGI output starts with, up to the blank line.
+// `None` when the block is malformed or too large.
+pub async fn read_head(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<Option<Vec<(String, String)>>> {
+    let mut headers = Vec::new();
+    let mut size = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = match reader.read_line(&mut line).await {
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        size += read;
+        if read == 0 || size > MAX_HEAD_SIZE {
+            return Ok(None);
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            return Ok(Some(headers));
+        }
+        match line.split_once(':') {
+            Some((name, value)) => {
+                headers.push((name.trim().to_string(), value.trim().to_string()))
+            }
+            None => return Ok(None),
+        }
+    }
+}
+
+// How to answer a CGI response (RFC 3875, section 6.2)
+#[derive(Debug, PartialEq, Eq)]
+pub enum CgiReply {
+    // Serve this local URI in place of the script
+    LocalRedirect(String),
+    Document {
+        status: HttpStatus,
+        headers: Vec<(String, String)>,
+    },
+}
+
+impl CgiReply {
+    // `None` when the `Status` field is not a final status code
+    pub fn new(headers: Vec<(String, String)>) -> Option<Self> {
+        let find = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        let status = match find("Status") {
+            Some(status) => {
+                let code = status.split_whitespace().next()?.parse().ok()?;
+                if code < 200 {
+                    return None;
+                }
+                Some(HttpStatus::from_code(code)?)
+            }
+            None => None,
+        };
+        let location = find("Location").map(str::to_string);
+        if let (None, Some(location)) = (status, &location) {
+            if location.starts_with('/') {
+                return Some(CgiReply::LocalRedirect(location.clone()));
+            }
+        }
+        let status = match (status, location) {
+            (Some(status), _) => status,
+            (None, Some(_)) => HttpStatus::Found,
+            (None, None) => HttpStatus::Ok,
+        };
+        Some(Self::document(status, headers))
+    }
+
+    // A response with `status` and the application's `headers`
+    pub fn document(status: HttpStatus, headers: Vec<(String, String)>) -> Self {
+        let has_content_type = headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("Content-Type"));
+        // Framing is the server's business, the rest is passed on
+        let mut headers: Vec<_> = headers
+            .into_iter()
+            .filter(|(name, _)| {
+                !["Status", "Transfer-Encoding", "Connection", "Keep-Alive"]
+                    .iter()
+                    .any(|skip| name.eq_ignore_ascii_case(skip))
+            })
+            .collect();
+        if !has_content_type && status.allows_body() {
+            headers.push(("Content-Type".into(), ContentType::Html.to_string()));
+        }
+        CgiReply::Document { status, headers }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, net::SocketAddr, path::Path};
+
+    use crate::{config::Config, http::request::Request};
+
+    use crate::http::response::HttpStatus;
+
+    use super::{
+        cgi_env, find_script, is_php_script, kill_group, read_head, reap, spawn, CgiConfig,
+        CgiReply, CgiScript,
+    };
+
+    #[test]
+    fn test_cgi_env() {
+        let req: Request = b"POST /app/index.php?a=1 HTTP/1.1\r\nHost: example.com:8080\r\n\
+            Content-Type: text/plain\r\nContent-Length: 5\r\nX-Token: t\r\nProxy: evil\r\n\r\nhello"
+            .to_vec()
+            .into();
+        let script = CgiScript {
+            script_name: "/app/index.php",
+            script_filename: Path::new("/srv/app/index.php"),
+            path_info: "",
+        };
+        let remote: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let local: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+        let env = cgi_env(&req, &script, &Config::default(), Some(remote), Some(local));
+        let get = |name: &str| env.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+
+        assert_eq!(Some("CGI/1.1"), get("GATEWAY_INTERFACE"));
+        assert_eq!(Some("POST"), get("REQUEST_METHOD"));
+        assert_eq!(Some("a=1"), get("QUERY_STRING"));
+        assert_eq!(Some("example.com"), get("SERVER_NAME"));
+        assert_eq!(Some("8080"), get("SERVER_PORT"));
+        assert_eq!(Some("/srv/app/index.php"), get("SCRIPT_FILENAME"));
+        assert_eq!(Some("10.0.0.1"), get("REMOTE_ADDR"));
+        assert_eq!(Some("text/plain"), get("CONTENT_TYPE"));
+        assert_eq!(Some("5"), get("CONTENT_LENGTH"));
+        assert_eq!(Some("t"), get("HTTP_X_TOKEN"));
+        assert_eq!(None, get("HTTP_PROXY"));
+        assert_eq!(None, get("HTTP_CONTENT_TYPE"));
+        assert_eq!(None, get("PATH_TRANSLATED"));
+    }
+
+    #[test]
+    fn test_cgi_config() {
+        let cgi = CgiConfig {
+            dirs: vec!["/cgi-bin/".into()],
+            interpreters: [("py".to_string(), "python3".into())].into(),
+            ..Default::default()
+        };
+        assert!(cgi.is_script("/cgi-bin/git"));
+        assert!(!cgi.is_script("/cgi-bin/"));
+        assert!(!cgi.is_script("/cgi-binary/x"));
+        assert!(cgi.is_script("/app/hello.py"));
+        assert!(!cgi.is_script("/app.py/hello"));
+        assert_eq!(Some(Path::new("python3")), cgi.interpreter("/a/b.py"));
+        assert_eq!(None, cgi.interpreter("/cgi-bin/git"));
+
+        assert!(cgi.handles("/cgi-bin/git/repo.git/info/refs"));
+        assert!(cgi.handles("/app/hello%2Epy/extra"));
+        assert!(!cgi.handles("/static/hello.pyc"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_find_script() {
+        let root = std::env::temp_dir().join(format!("minginx-cgi-{}", std::process::id()));
+        fs::create_dir_all(root.join("cgi-bin")).unwrap();
+        fs::write(root.join("cgi-bin/git"), "").unwrap();
+        let config = Config {
+            root: root.clone(),
+            ..Default::default()
+        };
+        let in_bin = |name: &str| name.starts_with("/cgi-bin/");
+
+        let script = find_script(&config, "/cgi-bin/git/repo%20a.git/info/refs", in_bin)
+            .await
+            .unwrap();
+        assert_eq!("/cgi-bin/git", script.script_name);
+        assert_eq!(
+            root.join("cgi-bin/git").canonicalize().unwrap(),
+            script.path
+        );
+        assert_eq!("/repo a.git/info/refs", script.path_info);
+
+        let script = find_script(&config, "/cgi-bin/git", in_bin).await.unwrap();
+        assert_eq!("", script.path_info);
+        assert!(find_script(&config, "/cgi-bin/missing/x", in_bin)
+            .await
+            .is_none());
+        assert!(find_script(&config, "/cgi-bin/../cgi-bin/git", in_bin)
+            .await
+            .is_none());
+
+        // PHP front controllers with a `PATH_INFO`, but nothing outside the root
+        fs::write(root.join("index.php"), "").unwrap();
+        let script = find_script(&config, "/index.php/users/1", is_php_script)
+            .await
+            .unwrap();
+        assert_eq!("/index.php", script.script_name);
+        assert_eq!("/users/1", script.path_info);
+        let outside = root.with_extension("outside.php");
+        fs::write(&outside, "").unwrap();
+        let _ = fs::remove_file(root.join("link.php"));
+        std::os::unix::fs::symlink(&outside, root.join("link.php")).unwrap();
+        assert!(find_script(&config, "/link.php", is_php_script)
+            .await
+            .is_none());
+        assert!(!is_php_script("/.php"));
+        assert!(!is_php_script("/a.php/b"));
+
+        fs::remove_file(&outside).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_spawn_limits() {
+        use tokio::io::AsyncReadExt;
+
+        let limits = CgiConfig {
+            open_files_limit: Some(64),
+            ..Default::default()
+        };
+        let script = Path::new("-c");
+        let command = Path::new("ulimit -n; cat");
+        let mut child = spawn(
+            Path::new("/bin/sh"),
+            &[script, command],
+            Vec::new(),
+            Path::new("/"),
+            b"body".to_vec(),
+            &limits,
+        )
+        .unwrap();
+        let mut output = String::new();
+        let mut stdout = child.stdout.take().unwrap();
+        stdout.read_to_string(&mut output).await.unwrap();
+        assert_eq!("64\nbody", output);
+
+        // Killing the group also stops what the script started
+        let command = Path::new("sleep 30 & wait");
+        let mut child = spawn(
+            Path::new("/bin/sh"),
+            &[script, command],
+            Vec::new(),
+            Path::new("/"),
+            Vec::new(),
+            &limits,
+        )
+        .unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        kill_group(&child);
+        child.wait().await.unwrap();
+        // The pipe closes only once the background `sleep` is gone too
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            stdout.read_to_end(&mut rest),
+        );
+        assert!(read.await.is_ok());
+
+        // A script that closes its output and keeps running is killed at
+        // the deadline
+        let command = Path::new("exec >&-; sleep 30");
+        let mut child = spawn(
+            Path::new("/bin/sh"),
+            &[script, command],
+            Vec::new(),
+            Path::new("/"),
+            Vec::new(),
+            &limits,
+        )
+        .unwrap();
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from_millis(100);
+        let reaped = tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            reap(&mut child, deadline),
+        );
+        assert!(reaped.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_read_head() {
+        let mut output = &b"Content-Type: text/plain\r\nX-A: 1\r\n\r\nhi"[..];
+        let headers = read_head(&mut output).await.unwrap().unwrap();
+        assert_eq!(
+            vec![
+                ("Content-Type".to_string(), "text/plain".to_string()),
+                ("X-A".to_string(), "1".to_string())
+            ],
+            headers
+        );
+        assert_eq!(b"hi", output);
+
+        let mut output = &b"Status: 404\n\n"[..];
+        assert_eq!(1, read_head(&mut output).await.unwrap().unwrap().len());
+        assert!(output.is_empty());
+        assert!(read_head(&mut &b"no header block"[..])
+            .await
+            .unwrap()
+            .is_none());
+        assert!(read_head(&mut &b"X-A: 1\r\n"[..]).await.unwrap().is_none());
+    }
+
+    #[test]
+    fn test_cgi_reply() {
+        let headers = |list: &[(&str, &str)]| {
+            list.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            Some(CgiReply::LocalRedirect("/other?a=1".into())),
+            CgiReply::new(headers(&[("Location", "/other?a=1")]))
+        );
+        assert_eq!(
+            Some(CgiReply::Document {
+                status: HttpStatus::Found,
+                headers: headers(&[
+                    ("Location", "https://example.com/"),
+                    ("Content-Type", "text/html; charset=utf-8")
+                ]),
+            }),
+            CgiReply::new(headers(&[("Location", "https://example.com/")]))
+        );
+        assert_eq!(
+            Some(CgiReply::Document {
+                status: HttpStatus::NotFound,
+                headers: headers(&[
+                    ("Content-Type", "application/json"),
+                    ("Set-Cookie", "a=1"),
+                    ("Set-Cookie", "b=2")
+                ]),
+            }),
+            CgiReply::new(headers(&[
+                ("Status", "404 Not Found"),
+                ("Content-Type", "application/json"),
+                ("Set-Cookie", "a=1"),
+                ("Set-Cookie", "b=2")
+            ]))
+        );
+        match CgiReply::new(headers(&[("Status", "301"), ("Location", "/new")])) {
+            Some(CgiReply::Document { status, .. }) => {
+                assert_eq!(HttpStatus::MovedPermanently, status)
+            }
+            reply => panic!("unexpected {reply:?}"),
+        }
+        // Only final status codes are taken
+        for status in ["0", "1000", "101", "abc"] {
+            assert_eq!(None, CgiReply::new(headers(&[("Status", status)])));
+        }
+    }
+}
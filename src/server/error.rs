@@ -0,0 +1,115 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::{fs, io::AsyncWriteExt, net::TcpStream};
+
+use crate::{
+    config::Config,
+    http::{
+        accept::negotiate,
+        escape::{html_escape, json_escape},
+        request::{HttpMethod, Request},
+        response::{ContentType, HttpStatus, Response},
+        uri::percent_decode,
+    },
+    SharedData,
+};
+
+use super::handlers::Handler;
+
+// An error response. The body is the configured error page for the status,
+// a built-in page when there is none, or JSON for clients that prefer it.
+pub struct HttpError<'a> {
+    pub status: HttpStatus,
+    pub path_buf: &'a [u8],
+    pub config: &'a Config,
+}
+
+#[async_trait]
+impl Handler for HttpError<'_> {
+    async fn handle(&self, stream: &mut TcpStream, _shared_data: Arc<Mutex<SharedData>>) {
+        let req: Request = self.path_buf.to_vec().into();
+        let accept = req.header("Accept");
+        let (body, content_type) = match negotiate(accept, &["text/html", "application/json"]) {
+            Some("application/json") => (
+                json_error(self.status, &percent_decode(req.uri_path())).into_bytes(),
+                "application/json".to_string(),
+            ),
+            _ => self.page().await,
+        };
+
+        let mut response = Response::new();
+        let response = response
+            .set_status(self.status)
+            .set_headers("Content-Type".into(), content_type)
+            .set_headers("Content-Length".into(), body.len().to_string())
+            .set_headers("Cache-Control".into(), "no-cache".into())
+            .add_vary("Accept")
+            .set_body(&body);
+        // Only files are served without a script or an upstream
+        if self.status == HttpStatus::MethodNotAllowed {
+            response.set_headers("Allow".into(), "GET, HEAD".into());
+        }
+
+        let bytes = match req.method() {
+            HttpMethod::Head => response.head_bytes(),
+            _ => response.as_bytes(),
+        };
+        if let Err(e) = stream.write_all(&bytes).await {
+            log::error!("Failed to send {}: {}", self.status, e);
+        }
+        let _ = stream.flush().await;
+    }
+}
+
+impl HttpError<'_> {
+    // The configured page and its content type, or the built-in page
+    async fn page(&self) -> (Vec<u8>, String) {
+        if let Some(path) = self.config.error_page(self.status.code()) {
+            match fs::read(&path).await {
+                Ok(content) => {
+                    let content_type = self.config.content_type(&path, &content);
+                    return (content, content_type);
+                }
+                Err(e) => log::error!("Failed to read error page {}: {}", path.display(), e),
+            }
+        }
+        (
+            fallback_page(self.status).into_bytes(),
+            ContentType::Html.to_string(),
+        )
+    }
+}
+
+pub fn fallback_page(status: HttpStatus) -> String {
+    let title = html_escape(&status.to_string());
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+         </head>\n<body>\n<h1>{title}</h1>\n<hr>\n<p>minginx</p>\n</body>\n</html>\n"
+    )
+}
+
+pub fn json_error(status: HttpStatus, path: &str) -> String {
+    format!(
+        "{{\"status\": {}, \"error\": \"{}\", \"path\": \"{}\"}}\n",
+        status.code(),
+        status.reason(),
+        json_escape(path)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::http::response::HttpStatus;
+
+    use super::{fallback_page, json_error};
+
+    #[test]
+    fn test_error_bodies() {
+        assert!(fallback_page(HttpStatus::NotFound).contains("<h1>404 Not Found</h1>"));
+        assert_eq!(
+            "{\"status\": 403, \"error\": \"Forbidden\", \"path\": \"/a\\\"b\"}\n",
+            json_error(HttpStatus::Forbidden, "/a\"b")
+        );
+    }
+}
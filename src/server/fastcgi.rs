@@ -0,0 +1,748 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Notify,
+    },
+    time::{timeout_at, Instant},
+};
+
+use super::upstream::{Address, Io};
+
+// Sending PHP requests to php-fpm or another FastCGI application
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FastCgiConfig {
+    // `127.0.0.1:9000` or `unix:/run/php/php-fpm.sock`; php-cgi is run
+    // for each request when unset
+    pub address: Option<String>,
+    // Connections kept open to the application
+    pub max_connections: usize,
+    // Extra parameters sent with every request, overriding the CGI ones
+    pub params: BTreeMap<String, String>,
+}
+
+impl Default for FastCgiConfig {
+    fn default() -> Self {
+        Self {
+            address: None,
+            max_connections: 16,
+            params: BTreeMap::new(),
+        }
+    }
+}
+
+// Record types (FastCGI specification, section 8)
+const BEGIN_REQUEST: u8 = 1;
+const ABORT_REQUEST: u8 = 2;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+// `protocolStatus` of a request refused because the connection is busy
+const CANT_MPX_CONN: u8 = 1;
+const MAX_CONTENT: usize = 65535;
+// Records buffered per request before the connection stops being read
+const QUEUE_LEN: usize = 16;
+// How long an aborted request may take to end before its connection is closed
+const ABORT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// One record with its 8-byte header and content padded to 8 bytes
+fn record(kind: u8, id: u16, content: &[u8]) -> Vec<u8> {
+    let padding = (8 - content.len() % 8) % 8;
+    let mut record = Vec::with_capacity(8 + content.len() + padding);
+    record.extend_from_slice(&[1, kind]);
+    record.extend_from_slice(&id.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.extend_from_slice(&[padding as u8, 0]);
+    record.extend_from_slice(content);
+    record.resize(record.len() + padding, 0);
+    record
+}
+
+// A stream of `kind` records carrying `data`, ended by an empty record
+fn stream_records(kind: u8, id: u16, data: &[u8]) -> Vec<u8> {
+    let mut records = Vec::new();
+    for chunk in data.chunks(MAX_CONTENT) {
+        records.extend(record(kind, id, chunk));
+    }
+    records.extend(record(kind, id, &[]));
+    records
+}
+
+fn encode_length(length: usize, out: &mut Vec<u8>) {
+    if length < 128 {
+        out.push(length as u8);
+    } else {
+        out.extend_from_slice(&(length as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+// Name-value pairs as sent in `PARAMS` and `GET_VALUES` records
+fn encode_pairs<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, value) in pairs {
+        encode_length(name.len(), &mut out);
+        encode_length(value.len(), &mut out);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+    out
+}
+
+fn decode_pairs(mut data: &[u8]) -> Option<Vec<(String, String)>> {
+    fn length(data: &mut &[u8]) -> Option<usize> {
+        let first = *data.first()?;
+        if first < 128 {
+            *data = &data[1..];
+            return Some(first as usize);
+        }
+        let bytes = data.get(..4)?;
+        let length = u32::from_be_bytes(bytes.try_into().ok()?) & 0x7fff_ffff;
+        *data = &data[4..];
+        Some(length as usize)
+    }
+
+    let mut pairs = Vec::new();
+    while !data.is_empty() {
+        let name_len = length(&mut data)?;
+        let value_len = length(&mut data)?;
+        let name = data.get(..name_len)?;
+        let value = data.get(name_len..name_len + value_len)?;
+        pairs.push((
+            String::from_utf8_lossy(name).into_owned(),
+            String::from_utf8_lossy(value).into_owned(),
+        ));
+        data = &data[name_len + value_len..];
+    }
+    Some(pairs)
+}
+
+// Read one record, `None` at the end of the stream
+async fn read_record(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<(u8, u16, Vec<u8>)>> {
+    let mut header = [0; 8];
+    match reader.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let id = u16::from_be_bytes([header[2], header[3]]);
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0; length + header[6] as usize];
+    reader.read_exact(&mut content).await?;
+    content.truncate(length);
+    Ok(Some((header[1], id, content)))
+}
+
+enum Event {
+    Stdout(Vec<u8>),
+    End,
+}
+
+#[derive(Default)]
+struct Streams {
+    // Requests in flight on the connection, by request id
+    senders: HashMap<u16, mpsc::Sender<Event>>,
+    closed: bool,
+}
+
+struct Connection {
+    writer: tokio::sync::Mutex<WriteHalf<Box<dyn Io>>>,
+    streams: Mutex<Streams>,
+    // Set once the application reports `FCGI_MPXS_CONNS=1`
+    multiplex: AtomicBool,
+    // Stops the read loop when the connection is closed from this side
+    closing: Notify,
+    abort_timeout: Duration,
+}
+
+impl Connection {
+    // Register a request and return its id, if the connection can take one
+    fn start(&self) -> Option<(u16, mpsc::Receiver<Event>)> {
+        let mut streams = self.streams.lock().unwrap();
+        if streams.closed
+            || (!streams.senders.is_empty() && !self.multiplex.load(Ordering::Relaxed))
+        {
+            return None;
+        }
+        let id = (1..=u16::MAX).find(|id| !streams.senders.contains_key(id))?;
+        let (sender, receiver) = mpsc::channel(QUEUE_LEN);
+        streams.senders.insert(id, sender);
+        Some((id, receiver))
+    }
+
+    fn is_closed(&self) -> bool {
+        self.streams.lock().unwrap().closed
+    }
+
+    fn close(&self) {
+        let mut streams = self.streams.lock().unwrap();
+        streams.closed = true;
+        // Dropping the senders ends every pending response
+        streams.senders.clear();
+        self.closing.notify_one();
+    }
+
+    async fn write(&self, data: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        let result = async {
+            writer.write_all(data).await?;
+            writer.flush().await
+        }
+        .await;
+        if result.is_err() {
+            self.close();
+        }
+        result
+    }
+
+    // Hand records to the requests they belong to until the connection ends
+    async fn read_loop(&self, mut reader: ReadHalf<Box<dyn Io>>, released: &Notify) {
+        loop {
+            let record = tokio::select! {
+                record = read_record(&mut reader) => record,
+                _ = self.closing.notified() => break,
+            };
+            let (kind, id, content) = match record {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) => {
+                    log::error!("FastCGI connection failed: {}", e);
+                    break;
+                }
+            };
+            match kind {
+                STDOUT if !content.is_empty() => {
+                    let sender = self.streams.lock().unwrap().senders.get(&id).cloned();
+                    let Some(sender) = sender else { continue };
+                    if !self.multiplex.load(Ordering::Relaxed) {
+                        // An abandoned request's output is dropped
+                        let _ = sender.send(Event::Stdout(content)).await;
+                    } else if let Err(TrySendError::Full(_)) =
+                        sender.try_send(Event::Stdout(content))
+                    {
+                        // A slow client must not hold up the other requests:
+                        // its response is cut off and the rest of its output
+                        // dropped until the application ends the request
+                        log::warn!("FastCGI request {} not read fast enough, dropping it", id);
+                        let (dropped, _) = mpsc::channel(1);
+                        self.streams.lock().unwrap().senders.insert(id, dropped);
+                    }
+                }
+                STDERR if !content.is_empty() => {
+                    log::warn!("FastCGI: {}", String::from_utf8_lossy(&content).trim_end());
+                }
+                END_REQUEST => {
+                    if content.get(4) == Some(&CANT_MPX_CONN) {
+                        self.multiplex.store(false, Ordering::Relaxed);
+                    }
+                    let sender = self.streams.lock().unwrap().senders.remove(&id);
+                    if let Some(sender) = sender {
+                        let _ = sender.send(Event::End).await;
+                    }
+                    released.notify_waiters();
+                }
+                GET_VALUES_RESULT => {
+                    let multiplex = decode_pairs(&content)
+                        .unwrap_or_default()
+                        .iter()
+                        .any(|(name, value)| name == "FCGI_MPXS_CONNS" && value == "1");
+                    self.multiplex.store(multiplex, Ordering::Relaxed);
+                    released.notify_waiters();
+                }
+                _ => {}
+            }
+        }
+        self.close();
+        released.notify_waiters();
+    }
+}
+
+#[derive(Default)]
+struct Pool {
+    connections: Vec<Arc<Connection>>,
+    // Connections being opened, counted against the limit
+    connecting: usize,
+}
+
+// Why a request could not be sent
+#[derive(Debug)]
+pub enum RequestError {
+    // No connection became free before the deadline
+    Busy,
+    // The application did not accept the request before the deadline
+    TimedOut,
+    Io(io::Error),
+}
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+// A FastCGI client keeping a pool of connections to one application.
+// Connections carry one request at a time unless the application supports
+// multiplexing.
+pub struct FastCgiClient {
+    address: Address,
+    max_connections: usize,
+    params: Vec<(String, String)>,
+    pool: Mutex<Pool>,
+    // Signalled when a request ends or a connection closes
+    released: Arc<Notify>,
+    abort_timeout: Duration,
+}
+
+impl FastCgiClient {
+    // `None` when no address is configured
+    pub fn new(config: &FastCgiConfig) -> Option<Self> {
+        Some(Self {
+            address: Address::parse(config.address.as_deref()?),
+            max_connections: config.max_connections.max(1),
+            params: config
+                .params
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            pool: Mutex::new(Pool::default()),
+            released: Arc::new(Notify::new()),
+            abort_timeout: ABORT_TIMEOUT,
+        })
+    }
+
+    // Send a responder request and return its stdout as a stream.
+    // `body` is written in the background while the output is read.
+    pub async fn request(
+        &self,
+        params: Vec<(String, String)>,
+        body: Vec<u8>,
+        deadline: Instant,
+    ) -> Result<FastCgiResponse, RequestError> {
+        let (connection, id, receiver) = self.acquire(deadline).await?;
+
+        let mut begin = RESPONDER.to_be_bytes().to_vec();
+        begin.extend_from_slice(&[KEEP_CONN, 0, 0, 0, 0, 0]);
+        let mut records = record(BEGIN_REQUEST, id, &begin);
+        let params = params
+            .iter()
+            .filter(|(name, _)| !self.params.iter().any(|(k, _)| k == name))
+            .chain(&self.params);
+        let params = encode_pairs(params.map(|(k, v)| (k.as_str(), v.as_str())));
+        records.extend(stream_records(PARAMS, id, &params));
+        match timeout_at(deadline, connection.write(&records)).await {
+            Ok(result) => result?,
+            Err(_) => {
+                // The id was taken without a response to free it
+                connection.close();
+                return Err(RequestError::TimedOut);
+            }
+        }
+
+        let stdin = Arc::clone(&connection);
+        tokio::spawn(async move {
+            if let Err(e) = stdin.write(&stream_records(STDIN, id, &body)).await {
+                log::error!("Failed to send request body over FastCGI: {}", e);
+            }
+        });
+
+        Ok(FastCgiResponse {
+            connection,
+            id,
+            receiver,
+            buffer: Vec::new(),
+            position: 0,
+            ended: false,
+        })
+    }
+
+    // A connection with a new request id, opening one if below the limit
+    async fn acquire(
+        &self,
+        deadline: Instant,
+    ) -> Result<(Arc<Connection>, u16, mpsc::Receiver<Event>), RequestError> {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            let connect = {
+                let mut pool = self.pool.lock().unwrap();
+                pool.connections
+                    .retain(|connection| !connection.is_closed());
+                for connection in &pool.connections {
+                    if let Some((id, receiver)) = connection.start() {
+                        return Ok((Arc::clone(connection), id, receiver));
+                    }
+                }
+                let connect = pool.connections.len() + pool.connecting < self.max_connections;
+                if connect {
+                    pool.connecting += 1;
+                }
+                connect
+            };
+            if connect {
+                let connection = timeout_at(deadline, self.connect()).await;
+                let mut pool = self.pool.lock().unwrap();
+                pool.connecting -= 1;
+                let connection = connection.map_err(|_| RequestError::TimedOut)??;
+                pool.connections.push(Arc::clone(&connection));
+                if let Some((id, receiver)) = connection.start() {
+                    return Ok((connection, id, receiver));
+                }
+                continue;
+            }
+            if timeout_at(deadline, released).await.is_err() {
+                return Err(RequestError::Busy);
+            }
+        }
+    }
+
+    async fn connect(&self) -> io::Result<Arc<Connection>> {
+        let (reader, writer) = split(self.address.connect().await?);
+        let connection = Arc::new(Connection {
+            writer: tokio::sync::Mutex::new(writer),
+            streams: Mutex::new(Streams::default()),
+            multiplex: AtomicBool::new(false),
+            closing: Notify::new(),
+            abort_timeout: self.abort_timeout,
+        });
+        let looping = Arc::clone(&connection);
+        let released = Arc::clone(&self.released);
+        tokio::spawn(async move { looping.read_loop(reader, &released).await });
+
+        // Applications that do not answer are used one request at a time
+        let query = encode_pairs([("FCGI_MPXS_CONNS", "")]);
+        connection.write(&record(GET_VALUES, 0, &query)).await?;
+        Ok(connection)
+    }
+}
+
+// The stdout of a FastCGI request, read as it arrives
+pub struct FastCgiResponse {
+    connection: Arc<Connection>,
+    id: u16,
+    receiver: mpsc::Receiver<Event>,
+    buffer: Vec<u8>,
+    position: usize,
+    ended: bool,
+}
+
+impl AsyncRead for FastCgiResponse {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.position < self.buffer.len() {
+                let size = buf.remaining().min(self.buffer.len() - self.position);
+                let start = self.position;
+                buf.put_slice(&self.buffer[start..start + size]);
+                self.position += size;
+                return Poll::Ready(Ok(()));
+            }
+            if self.ended {
+                return Poll::Ready(Ok(()));
+            }
+            match self.receiver.poll_recv(cx) {
+                Poll::Ready(Some(Event::Stdout(data))) => {
+                    self.buffer = data;
+                    self.position = 0;
+                }
+                Poll::Ready(Some(Event::End)) => self.ended = true,
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "FastCGI response cut off",
+                    )))
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for FastCgiResponse {
+    // Tell the application to stop a request whose output is not wanted.
+    // The id stays taken until it confirms with `END_REQUEST`; if that does
+    // not come in time the connection is closed so the pool can replace it.
+    fn drop(&mut self) {
+        if self.ended || self.connection.is_closed() {
+            return;
+        }
+        let connection = Arc::clone(&self.connection);
+        let id = self.id;
+        // Tells this request apart from a later one given the same id
+        let sender = connection.streams.lock().unwrap().senders.get(&id).cloned();
+        if let (Ok(handle), Some(sender)) = (tokio::runtime::Handle::try_current(), sender) {
+            handle.spawn(async move {
+                let _ = connection.write(&record(ABORT_REQUEST, id, &[])).await;
+                tokio::time::sleep(connection.abort_timeout).await;
+                let pending = connection
+                    .streams
+                    .lock()
+                    .unwrap()
+                    .senders
+                    .get(&id)
+                    .is_some_and(|pending| pending.same_channel(&sender));
+                if pending {
+                    log::warn!("FastCGI request {} not ended after abort, closing", id);
+                    connection.close();
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+        net::TcpListener,
+        time::Instant,
+    };
+
+    use super::{
+        decode_pairs, encode_pairs, read_record, record, stream_records, FastCgiClient,
+        FastCgiConfig, FastCgiResponse, RequestError, END_REQUEST, GET_VALUES, GET_VALUES_RESULT,
+        PARAMS, STDIN, STDOUT,
+    };
+
+    // A small FastCGI application: answers each request with its
+    // SCRIPT_NAME and request body once the body is complete
+    async fn stand_in(stream: impl AsyncRead + AsyncWrite + Unpin, multiplex: bool) {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let mut requests: HashMap<u16, (Vec<u8>, Vec<u8>)> = HashMap::new();
+        while let Ok(Some((kind, id, content))) = read_record(&mut reader).await {
+            match kind {
+                GET_VALUES => {
+                    let value = if multiplex { "1" } else { "0" };
+                    let pairs = encode_pairs([("FCGI_MPXS_CONNS", value)]);
+                    let reply = record(GET_VALUES_RESULT, 0, &pairs);
+                    writer.write_all(&reply).await.unwrap();
+                }
+                PARAMS => requests.entry(id).or_default().0.extend(content),
+                STDIN if !content.is_empty() => requests.entry(id).or_default().1.extend(content),
+                STDIN => {
+                    let (params, body) = requests.remove(&id).unwrap_or_default();
+                    let params: HashMap<_, _> =
+                        decode_pairs(&params).unwrap().into_iter().collect();
+                    let mut output = b"Content-Type: text/plain\r\n\r\n".to_vec();
+                    output.extend(
+                        params
+                            .get("SCRIPT_NAME")
+                            .cloned()
+                            .unwrap_or_default()
+                            .bytes(),
+                    );
+                    output.push(b' ');
+                    output.extend(params.get("APP").cloned().unwrap_or_default().bytes());
+                    output.push(b' ');
+                    output.extend(body);
+                    let mut reply = stream_records(STDOUT, id, &output);
+                    reply.extend(record(END_REQUEST, id, &[0; 8]));
+                    writer.write_all(&reply).await.unwrap();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Listen on a local port, counting accepted connections
+    async fn serve(multiplex: bool) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(stand_in(stream, multiplex));
+            }
+        });
+        (address, accepted)
+    }
+
+    fn new_client(address: &str, max_connections: usize) -> Arc<FastCgiClient> {
+        let config = FastCgiConfig {
+            address: Some(address.to_string()),
+            max_connections,
+            params: [("APP".to_string(), "prod".to_string())].into(),
+        };
+        Arc::new(FastCgiClient::new(&config).unwrap())
+    }
+
+    async fn send(client: &FastCgiClient, name: &str, body: &[u8]) -> FastCgiResponse {
+        let params = vec![
+            ("SCRIPT_NAME".to_string(), name.to_string()),
+            ("APP".to_string(), "dev".to_string()),
+        ];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        client
+            .request(params, body.to_vec(), deadline)
+            .await
+            .unwrap()
+    }
+
+    async fn get(client: &FastCgiClient, name: &str, body: &[u8]) -> String {
+        let mut response = send(client, name, body).await;
+        let mut output = String::new();
+        response.read_to_string(&mut output).await.unwrap();
+        output
+    }
+
+    #[test]
+    fn test_pairs() {
+        let long = "x".repeat(300);
+        let encoded = encode_pairs([("A", "1"), ("LONG", long.as_str())]);
+        assert_eq!(&[1, 1, b'A', b'1'], &encoded[..4]);
+        assert_eq!(&[4, 0x80, 0, 1, 44], &encoded[4..9]);
+        let decoded = decode_pairs(&encoded).unwrap();
+        assert_eq!(("LONG".to_string(), long), decoded[1]);
+        assert!(decode_pairs(&[5, 1, b'A']).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fastcgi_request() {
+        let (address, accepted) = serve(false).await;
+        let client = new_client(&address, 4);
+
+        let body = vec![b'b'; 70000];
+        let output = get(&client, "/a.php", &body).await;
+        assert!(output.starts_with("Content-Type: text/plain\r\n\r\n/a.php prod bbb"));
+        assert_eq!(28 + 12 + 70000, output.len());
+
+        // The idle connection is reused
+        assert_eq!(
+            "Content-Type: text/plain\r\n\r\n/b.php prod ",
+            get(&client, "/b.php", b"").await
+        );
+        assert_eq!(1, accepted.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_fastcgi_pool() {
+        // Without multiplexing concurrent requests open more connections,
+        // up to the limit
+        let (address, accepted) = serve(false).await;
+        let client = new_client(&address, 2);
+        let tasks: Vec<_> = (0..6)
+            .map(|i| {
+                let client = Arc::clone(&client);
+                tokio::spawn(async move { get(&client, &format!("/{i}"), b"x").await })
+            })
+            .collect();
+        for (i, task) in tasks.into_iter().enumerate() {
+            assert!(task.await.unwrap().ends_with(&format!("/{i} prod x")));
+        }
+        assert!(accepted.load(Ordering::SeqCst) <= 2);
+
+        // A multiplexing application gets concurrent requests over one
+        let (address, accepted) = serve(true).await;
+        let client = new_client(&address, 1);
+        get(&client, "/warm", b"").await;
+        let (a, b) = tokio::join!(get(&client, "/a", b"1"), get(&client, "/b", b"2"));
+        assert!(a.ends_with("/a prod 1") && b.ends_with("/b prod 2"));
+        assert_eq!(1, accepted.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_fastcgi_slow_reader() {
+        // A response left unread does not hold up the others on a
+        // multiplexed connection
+        let (address, _) = serve(true).await;
+        let client = new_client(&address, 1);
+        get(&client, "/warm", b"").await;
+        let mut unread = send(&client, "/big", &vec![b'b'; 40 * 65535]).await;
+        let other = get(&client, "/small", b"s");
+        let other = tokio::time::timeout(Duration::from_secs(5), other).await;
+        assert!(other
+            .expect("blocked by the unread response")
+            .ends_with("/small prod s"));
+        let mut output = Vec::new();
+        assert!(unread.read_to_end(&mut output).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fastcgi_abort() {
+        // An application that never ends its requests
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(
+                    async move { while let Ok(Some(_)) = read_record(&mut stream).await {} },
+                );
+            }
+        });
+        let config = FastCgiConfig {
+            address: Some(address),
+            max_connections: 1,
+            ..Default::default()
+        };
+        let mut client = FastCgiClient::new(&config).unwrap();
+        client.abort_timeout = Duration::from_millis(100);
+
+        // Requests waiting for the busy connection give up at the deadline
+        let first = send(&client, "/a.php", b"").await;
+        let params = vec![("SCRIPT_NAME".to_string(), "/b.php".to_string())];
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let busy = client.request(params.clone(), Vec::new(), deadline).await;
+        assert!(matches!(busy, Err(RequestError::Busy)));
+
+        // The abandoned request's connection is closed and replaced
+        drop(first);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let request = client.request(params, Vec::new(), deadline).await;
+        assert!(request.is_ok(), "connection still taken");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_fastcgi_unix() {
+        let path = std::env::temp_dir().join(format!("minginx-fcgi-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(stand_in(stream, false));
+            }
+        });
+        let client = new_client(&format!("unix:{}", path.display()), 1);
+        assert!(get(&client, "/u.php", b"hi")
+            .await
+            .ends_with("/u.php prod hi"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
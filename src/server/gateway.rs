@@ -0,0 +1,244 @@
+use std::{collections::BTreeMap, io};
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::http::response::HttpStatus;
+
+use super::cgi::{read_head, CgiReply};
+
+// An application mounted at `prefix`, spoken to over SCGI or uwsgi.
+// The prefix becomes `SCRIPT_NAME` and the rest of the path `PATH_INFO`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GatewayConfig {
+    pub prefix: String,
+    pub protocol: Protocol,
+    // `127.0.0.1:4000` or `unix:/run/uwsgi/app.sock`
+    pub address: String,
+    // Extra variables sent with every request, overriding the CGI ones
+    #[serde(default)]
+    pub params: BTreeMap<String, String>,
+    // Seconds to wait for the application to answer
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // Largest request body passed on, unlimited by default. Chunked bodies
+    // are read first to learn their length, up to `client_max_body_size`.
+    pub client_max_body_size: Option<u64>,
+}
+
+fn default_timeout() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Scgi,
+    Uwsgi,
+}
+
+// SCGI request head: the variables as a netstring, `CONTENT_LENGTH` first
+pub fn encode_scgi(env: &[(String, String)], body_len: u64) -> Vec<u8> {
+    let mut headers = Vec::new();
+    let length = body_len.to_string();
+    let fixed = [("CONTENT_LENGTH", length.as_str()), ("SCGI", "1")];
+    let rest = env
+        .iter()
+        .filter(|(name, _)| name != "CONTENT_LENGTH" && name != "SCGI")
+        .map(|(k, v)| (k.as_str(), v.as_str()));
+    for (name, value) in fixed.into_iter().chain(rest) {
+        headers.extend_from_slice(name.as_bytes());
+        headers.push(0);
+        headers.extend_from_slice(value.as_bytes());
+        headers.push(0);
+    }
+    let mut head = format!("{}:", headers.len()).into_bytes();
+    head.extend(headers);
+    head.push(b',');
+    head
+}
+
+// uwsgi request head: a 4-byte packet header and the variables with 16-bit
+// little-endian lengths. `None` when they exceed the 64 KiB packet limit.
+pub fn encode_uwsgi(env: &[(String, String)]) -> Option<Vec<u8>> {
+    let mut vars = Vec::new();
+    for (name, value) in env {
+        for part in [name, value] {
+            vars.extend_from_slice(&u16::try_from(part.len()).ok()?.to_le_bytes());
+            vars.extend_from_slice(part.as_bytes());
+        }
+    }
+    let mut head = vec![0];
+    head.extend_from_slice(&u16::try_from(vars.len()).ok()?.to_le_bytes());
+    head.push(0);
+    head.extend(vars);
+    Some(head)
+}
+
+// The request head in `protocol`, sent before a body of `body_len` bytes
+pub fn encode_head(
+    protocol: Protocol,
+    env: &[(String, String)],
+    body_len: u64,
+) -> io::Result<Vec<u8>> {
+    match protocol {
+        Protocol::Scgi => Ok(encode_scgi(env, body_len)),
+        Protocol::Uwsgi => encode_uwsgi(env).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "uwsgi variables too large")
+        }),
+    }
+}
+
+// Read the response head: CGI-style header fields for SCGI, an HTTP status
+// line and header fields for uwsgi. `None` when it is malformed.
+pub async fn read_reply(
+    protocol: Protocol,
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<Option<CgiReply>> {
+    match protocol {
+        Protocol::Scgi => Ok(read_head(reader).await?.and_then(CgiReply::new)),
+        Protocol::Uwsgi => {
+            let mut line = String::new();
+            reader.read_line(&mut line).await?;
+            let status = line
+                .strip_prefix("HTTP/1.")
+                .and_then(|rest| rest.split_whitespace().nth(1))
+                .and_then(|code| code.parse().ok())
+                .filter(|code| *code >= 200)
+                .and_then(HttpStatus::from_code);
+            let Some(status) = status else {
+                return Ok(None);
+            };
+            let headers = read_head(reader).await?;
+            Ok(headers.map(|headers| CgiReply::document(status, headers)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::{
+        http::response::HttpStatus,
+        server::{
+            cgi::CgiReply,
+            upstream::{send_request, Address},
+        },
+    };
+
+    use super::{encode_head, encode_uwsgi, read_reply, Protocol};
+
+    // Read a request in `protocol` and return its variables and body
+    async fn parse(
+        protocol: Protocol,
+        stream: &mut TcpStream,
+    ) -> (HashMap<String, String>, Vec<u8>) {
+        let mut vars = HashMap::new();
+        match protocol {
+            Protocol::Scgi => {
+                let mut length = Vec::new();
+                loop {
+                    let byte = stream.read_u8().await.unwrap();
+                    if byte == b':' {
+                        break;
+                    }
+                    length.push(byte);
+                }
+                let length: usize = String::from_utf8(length).unwrap().parse().unwrap();
+                let mut headers = vec![0; length + 1];
+                stream.read_exact(&mut headers).await.unwrap();
+                assert_eq!(Some(&b','), headers.last());
+                let fields: Vec<_> = headers[..length].split(|&b| b == 0).collect();
+                assert_eq!(b"CONTENT_LENGTH", fields[0]);
+                for pair in fields.chunks_exact(2) {
+                    let name = String::from_utf8_lossy(pair[0]).into_owned();
+                    vars.insert(name, String::from_utf8_lossy(pair[1]).into_owned());
+                }
+            }
+            Protocol::Uwsgi => {
+                let mut header = [0; 4];
+                stream.read_exact(&mut header).await.unwrap();
+                let size = u16::from_le_bytes([header[1], header[2]]) as usize;
+                let mut packet = vec![0; size];
+                stream.read_exact(&mut packet).await.unwrap();
+                let mut parts = Vec::new();
+                let mut rest = &packet[..];
+                while !rest.is_empty() {
+                    let length = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+                    parts.push(String::from_utf8_lossy(&rest[2..2 + length]).into_owned());
+                    rest = &rest[2 + length..];
+                }
+                for pair in parts.chunks_exact(2) {
+                    vars.insert(pair[0].clone(), pair[1].clone());
+                }
+            }
+        }
+        let length = vars["CONTENT_LENGTH"].parse().unwrap();
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        (vars, body)
+    }
+
+    // A stand-in application answering with PATH_INFO and the request body
+    async fn stand_in(protocol: Protocol) -> Address {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address::Tcp(listener.local_addr().unwrap().to_string());
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (vars, body) = parse(protocol, &mut stream).await;
+            let head = match protocol {
+                Protocol::Scgi => "Status: 201 Created\r\nContent-Type: text/plain\r\n\r\n",
+                Protocol::Uwsgi => "HTTP/1.1 201 Created\r\nContent-Type: text/plain\r\n\r\n",
+            };
+            let reply = format!("{}{} ", head, vars["PATH_INFO"]);
+            stream.write_all(reply.as_bytes()).await.unwrap();
+            stream.write_all(&body).await.unwrap();
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_gateway_protocols() {
+        for protocol in [Protocol::Scgi, Protocol::Uwsgi] {
+            let address = stand_in(protocol).await;
+            let env = vec![
+                ("REQUEST_METHOD".to_string(), "POST".to_string()),
+                ("CONTENT_LENGTH".to_string(), "100000".to_string()),
+                ("PATH_INFO".to_string(), "/users/1".to_string()),
+            ];
+            let body = vec![b'x'; 100_000];
+            let head = encode_head(protocol, &env, body.len() as u64).unwrap();
+            let reader = send_request(&address, &head, body).await.unwrap();
+            let mut reader = BufReader::new(reader);
+            let reply = read_reply(protocol, &mut reader).await.unwrap().unwrap();
+            assert_eq!(
+                CgiReply::Document {
+                    status: HttpStatus::Created,
+                    headers: vec![("Content-Type".into(), "text/plain".into())],
+                },
+                reply
+            );
+            let mut output = Vec::new();
+            reader.read_to_end(&mut output).await.unwrap();
+            assert!(output.starts_with(b"/users/1 xxx"));
+            assert_eq!(9 + 100_000, output.len());
+        }
+    }
+
+    #[test]
+    fn test_encode_uwsgi() {
+        let env = vec![("A".to_string(), "bc".to_string())];
+        assert_eq!(
+            vec![0, 7, 0, 0, 1, 0, b'A', 2, 0, b'b', b'c'],
+            encode_uwsgi(&env).unwrap()
+        );
+        let env = vec![("A".to_string(), "x".repeat(70_000))];
+        assert!(encode_uwsgi(&env).is_none());
+    }
+}
@@ -1,31 +1,108 @@
-use std::sync::{Arc, Mutex};
+use std::{
+    io::{self, Read},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
 use async_trait::async_trait;
 
-use tokio::process::Command;
-
-use tokio::{fs, io::AsyncWriteExt, net::TcpStream};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWriteExt, BufReader, ReadHalf, WriteHalf},
+    net::TcpStream,
+    sync::OwnedSemaphorePermit,
+    time::{timeout, timeout_at, Instant},
+};
 
 use crate::{
+    config::{CacheRule, Config},
     http::{
-        request::Request,
+        accept::negotiate as negotiate_type,
+        chunked::ChunkedReader,
+        conditional::{evaluate, if_range_matches, make_etag, EtagMode, Precondition, Validators},
+        date::fmt_http_date,
+        encoding::{negotiate, Encoding},
+        escape::json_escape,
+        mime::with_charset,
+        range::{
+            boundary, content_range, multipart_body, multipart_length, multipart_part_header,
+            multipart_trailer, parse_range, RangeRequest,
+        },
+        request::{HttpMethod, Request},
         response::{ContentType, HttpStatus, Response},
+        uri::{has_dot_segment, percent_decode, percent_encode},
     },
     SharedData,
 };
 
-pub struct NotFound;
+use super::{
+    archive::{collect, write_archive, ArchiveFormat},
+    autoindex::{
+        read_entries, render_html, render_json, render_xml, sort_entries, Format, Order, SortKey,
+    },
+    balancer::{Peer, Upstreams},
+    cache::FileBody,
+    cgi::{
+        self, cgi_env, find_script, is_php_script, leads_to_script, read_head, script_at, CgiReply,
+        CgiScript, FoundScript,
+    },
+    error::HttpError,
+    fastcgi::RequestError,
+    gateway::{encode_head, read_reply, GatewayConfig},
+    proxy::{end_to_end, forward_head, read_response_head, ProxyConfig},
+    reader::{ReadError, RequestBody},
+    send::{send, send_multipart, send_reader, send_stream},
+    upstream::{exchange, open, send_body, Address, Io},
+};
+
+// Bytes of a streamed file inspected when sniffing its type
+const SNIFF_LEN: usize = 512;
+
+// Internal redirects followed for one request, as many as nginx allows
+const MAX_REDIRECTS: u32 = 10;
+
 pub struct VisitCount;
+pub struct Status;
 pub struct Echo<'a> {
     pub path_buf: &'a [u8],
 }
 
 pub struct PHPFile<'a> {
     pub path_buf: &'a [u8],
+    pub config: &'a Config,
+    // Internal redirects followed to get here
+    pub redirects: u32,
+}
+
+// A script run through `[cgi]` directories or interpreters
+pub struct CgiFile<'a> {
+    pub path_buf: &'a [u8],
+    pub config: &'a Config,
+    pub redirects: u32,
+}
+
+// An SCGI or uwsgi application mounted with `[[gateway]]`
+pub struct Gateway<'a> {
+    pub path_buf: &'a [u8],
+    pub config: &'a Config,
+    pub gateway: &'a GatewayConfig,
+    pub redirects: u32,
+}
+
+// Requests forwarded to an HTTP server with `[[proxy]]`
+pub struct Proxy<'a> {
+    pub path_buf: &'a [u8],
+    pub config: &'a Config,
+    pub proxy: &'a ProxyConfig,
 }
 
 pub struct StaticFile<'a> {
     pub path_buf: &'a [u8],
+    pub config: &'a Config,
+    // Missing files can lead to the front controller
+    pub redirects: u32,
 }
 
 #[async_trait]
@@ -36,29 +113,732 @@ pub trait Handler {
 #[async_trait]
 impl Handler for PHPFile<'_> {
     async fn handle(&self, stream: &mut TcpStream, shared_data: Arc<Mutex<SharedData>>) {
-        let buf = &String::from_utf8_lossy(self.path_buf); // Buffer to string
-        let start_index = buf.find("GET ").unwrap() + 4;
-        let end_index = buf.find(" HTTP/").unwrap();
-        let mut path = String::from(&buf[start_index..end_index]);
-        path = format!(".{}", path);
-        let output = Command::new("php").arg(&path).output().await.unwrap();
+        let req: Request = self.path_buf.to_vec().into();
+        if self.config.access.denied(&percent_decode(req.uri_path())) {
+            return deny(self.config, self.path_buf, stream, shared_data).await;
+        }
+        match find_script(self.config, req.uri_path(), is_php_script).await {
+            Some(script) => self.execute(stream, shared_data, &script).await,
+            None => self.front_controller(stream, shared_data).await,
+        }
+    }
+}
+
+impl PHPFile<'_> {
+    fn error(&self, status: HttpStatus) -> HttpError<'_> {
+        HttpError {
+            status,
+            path_buf: self.path_buf,
+            config: self.config,
+        }
+    }
+
+    // Run the configured front controller for a request matching no file,
+    // keeping its `REQUEST_URI`
+    pub async fn front_controller(
+        &self,
+        stream: &mut TcpStream,
+        shared_data: Arc<Mutex<SharedData>>,
+    ) {
+        let script = match &self.config.php_front_controller {
+            Some(uri_path) => find_script(self.config, uri_path, is_php_script).await,
+            None => None,
+        };
+        match script {
+            Some(script) => self.execute(stream, shared_data, &script).await,
+            None => {
+                self.error(HttpStatus::NotFound)
+                    .handle(stream, shared_data)
+                    .await
+            }
+        }
+    }
+
+    // Run `script` through the FastCGI application, or php-cgi when none is
+    // configured, and send its output
+    pub async fn execute(
+        &self,
+        stream: &mut TcpStream,
+        shared_data: Arc<Mutex<SharedData>>,
+        script: &FoundScript,
+    ) {
+        let req: Request = self.path_buf.to_vec().into();
+        let cgi_script = CgiScript {
+            script_name: &script.script_name,
+            script_filename: &script.path,
+            path_info: &script.path_info,
+        };
+        let script = script.path.as_path();
+        let env = cgi_env(
+            &req,
+            &cgi_script,
+            self.config,
+            stream.peer_addr().ok(),
+            stream.local_addr().ok(),
+        );
+        let body = req.body().to_vec();
+        let fastcgi = shared_data.lock().unwrap().fastcgi.clone();
+        let deadline = Instant::now() + Duration::from_secs(self.config.cgi.timeout);
+        let mut permit = None;
+        // php-cgi is waited for once its output has been sent
+        let (output, mut child): (Box<dyn AsyncRead + Send + Unpin>, _) = match fastcgi {
+            Some(client) => match client.request(env, body, deadline).await {
+                Ok(response) => (Box::new(response), None),
+                Err(e) => {
+                    let status = match e {
+                        RequestError::Busy => {
+                            log::warn!("All FastCGI connections busy");
+                            HttpStatus::ServiceUnavailable
+                        }
+                        RequestError::TimedOut => {
+                            log::error!("FastCGI request for {} timed out", script.display());
+                            HttpStatus::GatewayTimeout
+                        }
+                        RequestError::Io(e) => {
+                            log::error!("FastCGI request for {} failed: {}", script.display(), e);
+                            HttpStatus::BadGateway
+                        }
+                    };
+                    return self.error(status).handle(stream, shared_data).await;
+                }
+            },
+            None => {
+                permit = match process_slot(&shared_data, self.config).await {
+                    Ok(permit) => permit,
+                    Err(status) => return self.error(status).handle(stream, shared_data).await,
+                };
+                let dir = script.parent().unwrap_or(Path::new("."));
+                match cgi::spawn(&self.config.php_cgi, &[], env, dir, body, &self.config.cgi) {
+                    Ok(mut child) => match child.stdout.take() {
+                        Some(stdout) => (Box::new(stdout), Some(child)),
+                        None => return,
+                    },
+                    Err(e) => {
+                        log::error!("Failed to run {}: {}", self.config.php_cgi.display(), e);
+                        return self
+                            .error(HttpStatus::InternalServerError)
+                            .handle(stream, shared_data)
+                            .await;
+                    }
+                }
+            }
+        };
+        let cgi_output = CgiOutput {
+            path_buf: self.path_buf,
+            config: self.config,
+            script,
+            deadline,
+        };
+        let outcome = cgi_output
+            .send(stream, shared_data.clone(), &req, output)
+            .await;
+        if let Some(child) = child.as_mut() {
+            cgi::reap(child, deadline).await;
+        }
+        drop(permit);
+        if let Outcome::Redirect(location) = outcome {
+            internal_redirect(
+                stream,
+                shared_data,
+                self.config,
+                &req,
+                &location,
+                self.redirects,
+            )
+            .await;
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for CgiFile<'_> {
+    async fn handle(&self, stream: &mut TcpStream, shared_data: Arc<Mutex<SharedData>>) {
+        let req: Request = self.path_buf.to_vec().into();
+        if self.config.access.denied(&percent_decode(req.uri_path())) {
+            return deny(self.config, self.path_buf, stream, shared_data).await;
+        }
+        let cgi = &self.config.cgi;
+        let Some(script) =
+            find_script(self.config, req.uri_path(), |name| cgi.is_script(name)).await
+        else {
+            return self
+                .error(HttpStatus::NotFound)
+                .handle(stream, shared_data)
+                .await;
+        };
+
+        let cgi_script = CgiScript {
+            script_name: &script.script_name,
+            script_filename: &script.path,
+            path_info: &script.path_info,
+        };
+        let mut env = cgi_env(
+            &req,
+            &cgi_script,
+            self.config,
+            stream.peer_addr().ok(),
+            stream.local_addr().ok(),
+        );
+        env.extend(cgi.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+        // Scripts without an interpreter are run themselves
+        let (program, args) = match cgi.interpreter(&script.script_name) {
+            Some(interpreter) => (interpreter, vec![script.path.as_path()]),
+            None => (script.path.as_path(), Vec::new()),
+        };
+        let permit = match process_slot(&shared_data, self.config).await {
+            Ok(permit) => permit,
+            Err(status) => return self.error(status).handle(stream, shared_data).await,
+        };
+        let dir = script.path.parent().unwrap_or(Path::new("."));
+        let body = req.body().to_vec();
+        let mut child = match cgi::spawn(program, &args, env, dir, body, cgi) {
+            Ok(child) => child,
+            Err(e) => {
+                log::error!("Failed to run {}: {}", script.path.display(), e);
+                let status = match e.kind() {
+                    io::ErrorKind::PermissionDenied => HttpStatus::Forbidden,
+                    _ => HttpStatus::InternalServerError,
+                };
+                return self.error(status).handle(stream, shared_data).await;
+            }
+        };
+        let deadline = Instant::now() + Duration::from_secs(cgi.timeout);
+        let mut outcome = Outcome::Sent;
+        if let Some(stdout) = child.stdout.take() {
+            let cgi_output = CgiOutput {
+                path_buf: self.path_buf,
+                config: self.config,
+                script: &script.path,
+                deadline,
+            };
+            outcome = cgi_output
+                .send(stream, shared_data.clone(), &req, stdout)
+                .await;
+        }
+        cgi::reap(&mut child, deadline).await;
+        drop(permit);
+        if let Outcome::Redirect(location) = outcome {
+            internal_redirect(
+                stream,
+                shared_data,
+                self.config,
+                &req,
+                &location,
+                self.redirects,
+            )
+            .await;
+        }
+    }
+}
+
+impl CgiFile<'_> {
+    fn error(&self, status: HttpStatus) -> HttpError<'_> {
+        HttpError {
+            status,
+            path_buf: self.path_buf,
+            config: self.config,
+        }
+    }
+}
 
-        if !output.status.success() {
-            log::error!("PHP execution failed {}", String::from_utf8_lossy(&output.stderr));
+#[async_trait]
+impl Handler for Gateway<'_> {
+    async fn handle(&self, stream: &mut TcpStream, shared_data: Arc<Mutex<SharedData>>) {
+        let req: Request = self.path_buf.to_vec().into();
+        let uri_path = percent_decode(req.uri_path());
+        if self.config.access.denied(&uri_path) {
+            return deny(self.config, self.path_buf, stream, shared_data).await;
+        }
+        let gateway = self.gateway;
+        // The prefix names the application, the rest is its own path
+        let script_name = gateway.prefix.trim_end_matches('/');
+        let cgi_script = CgiScript {
+            script_name,
+            script_filename: Path::new(""),
+            path_info: uri_path.get(script_name.len()..).unwrap_or(""),
+        };
+        let mut env = cgi_env(
+            &req,
+            &cgi_script,
+            self.config,
+            stream.peer_addr().ok(),
+            stream.local_addr().ok(),
+        );
+        env.retain(|(name, _)| name != "SCRIPT_FILENAME");
+        for (name, value) in &gateway.params {
+            env.retain(|(other, _)| other != name);
+            env.push((name.clone(), value.clone()));
+        }
 
-            NotFound.handle(stream, Arc::clone(&shared_data)).await;
+        let address = Address::parse(&gateway.address);
+        let wait = Duration::from_secs(gateway.timeout);
+        let exchanged = async {
+            let mut body = RequestBody::new(&req, stream, gateway.client_max_body_size)
+                .await
+                .map_err(GatewayError::Body)?;
+            // Both protocols send the length of the body before it
+            body.buffer(self.config.client_max_body_size)
+                .await
+                .map_err(GatewayError::Body)?;
+            let length = body.length().unwrap_or(0);
+            env.retain(|(name, _)| name != "CONTENT_LENGTH");
+            if length > 0 || req.header("Content-Length").is_some() {
+                env.push(("CONTENT_LENGTH".into(), length.to_string()));
+            }
+            let head = encode_head(gateway.protocol, &env, length).map_err(GatewayError::Io)?;
+            let (reader, mut writer) = timeout(wait, open(&address, &head))
+                .await
+                .map_err(|_| GatewayError::TimedOut)?
+                .map_err(GatewayError::Io)?;
+            let mut output = BufReader::new(reader);
+            let sending = send_body(&mut body, &mut writer);
+            let reply = read_reply(gateway.protocol, &mut output);
+            match exchange(sending, reply, wait).await {
+                Ok(Some(Ok(Some(reply)))) => Ok((reply, output)),
+                Ok(Some(Ok(None))) => Err(GatewayError::Invalid),
+                Ok(Some(Err(e))) => Err(GatewayError::Io(e)),
+                Ok(None) => Err(GatewayError::TimedOut),
+                Err(e) => Err(GatewayError::Body(e)),
+            }
+        };
+        let (reply, output) = match exchanged.await {
+            Ok(exchanged) => exchanged,
+            Err(GatewayError::Body(e)) => {
+                log::info!(
+                    "Failed to read the request body for {}: {}",
+                    gateway.address,
+                    e
+                );
+                if let Some(status) = e.status() {
+                    self.error(status).handle(stream, shared_data).await;
+                }
+                return;
+            }
+            Err(GatewayError::Io(e)) => {
+                log::error!("Request to {} failed: {}", gateway.address, e);
+                return self
+                    .error(HttpStatus::BadGateway)
+                    .handle(stream, shared_data)
+                    .await;
+            }
+            Err(GatewayError::Invalid) => {
+                log::error!("{} sent no valid response head", gateway.address);
+                return self
+                    .error(HttpStatus::BadGateway)
+                    .handle(stream, shared_data)
+                    .await;
+            }
+            Err(GatewayError::TimedOut) => {
+                log::error!("{} timed out", gateway.address);
+                return self
+                    .error(HttpStatus::GatewayTimeout)
+                    .handle(stream, shared_data)
+                    .await;
+            }
+        };
+        // The output has as long again to be sent
+        let deadline = Instant::now() + wait;
+        let gateway_output = CgiOutput {
+            path_buf: self.path_buf,
+            config: self.config,
+            script: Path::new(&gateway.address),
+            deadline,
+        };
+        let outcome = gateway_output.reply(stream, &req, reply, output).await;
+        if let Outcome::Redirect(location) = outcome {
+            internal_redirect(
+                stream,
+                shared_data,
+                self.config,
+                &req,
+                &location,
+                self.redirects,
+            )
+            .await;
         }
+    }
+}
+
+// Why a gateway request got no reply
+#[derive(Debug)]
+enum GatewayError {
+    // Reading the client's body failed
+    Body(ReadError),
+    Io(io::Error),
+    TimedOut,
+    Invalid,
+}
 
-        let out = String::from_utf8_lossy(&output.stdout);
+impl Gateway<'_> {
+    fn error(&self, status: HttpStatus) -> HttpError<'_> {
+        HttpError {
+            status,
+            path_buf: self.path_buf,
+            config: self.config,
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for Proxy<'_> {
+    async fn handle(&self, stream: &mut TcpStream, shared_data: Arc<Mutex<SharedData>>) {
+        let req: Request = self.path_buf.to_vec().into();
+        if self.config.access.denied(&percent_decode(req.uri_path())) {
+            return deny(self.config, self.path_buf, stream, shared_data).await;
+        }
+        let proxy = self.proxy;
+        let upstreams = shared_data.lock().unwrap().upstreams.clone();
+        let client = stream.peer_addr().ok();
+        // The chosen server of a group stays busy until the response is sent
+        let (upstream, mut writer, peer) = match self.connect(&upstreams, &req, client).await {
+            Ok(connected) => connected,
+            Err(status) => return self.error(status).handle(stream, shared_data).await,
+        };
+        let server = peer
+            .as_ref()
+            .map_or(proxy.pass.as_str(), |peer| peer.name());
+
+        let mut upstream = BufReader::new(upstream);
+        let exchanged = async {
+            let mut body = RequestBody::new(&req, stream, proxy.client_max_body_size).await?;
+            let sending = send_body(&mut body, &mut writer);
+            let response = read_response_head(&mut upstream);
+            exchange(sending, response, Duration::from_secs(proxy.timeout)).await
+        };
+        let head = match exchanged.await {
+            Ok(head) => head,
+            Err(e) => {
+                log::info!("Failed to read the request body for {}: {}", server, e);
+                if let Some(status) = e.status() {
+                    self.error(status).handle(stream, shared_data).await;
+                }
+                return;
+            }
+        };
+        if let Some(peer) = &peer {
+            match &head {
+                Some(Ok(Some(_))) => peer.succeeded(),
+                _ => peer.failed(),
+            }
+        }
+        let (status, headers) = match head {
+            Some(Ok(Some(head))) => head,
+            Some(result) => {
+                if let Err(e) = result {
+                    log::error!("Failed to read the response of {}: {}", server, e);
+                } else {
+                    log::error!("{} sent no valid response head", server);
+                }
+                return self
+                    .error(HttpStatus::BadGateway)
+                    .handle(stream, shared_data)
+                    .await;
+            }
+            None => {
+                log::error!("{} timed out", server);
+                return self
+                    .error(HttpStatus::GatewayTimeout)
+                    .handle(stream, shared_data)
+                    .await;
+            }
+        };
+
+        let chunked = headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("Transfer-Encoding")
+                && value.to_ascii_lowercase().contains("chunked")
+        });
         let mut response = Response::new();
-        let response = response
-            .set_status(HttpStatus::Ok)
-            .set_headers("Content-Type".into(), ContentType::Html.to_string())
-            .set_headers("Content-Length".into(), out.len().to_string())
-            .set_body(&out.as_bytes());
+        response.set_status(status);
+        for (name, value) in end_to_end(&headers) {
+            // A chunked body is sent on with its own framing
+            if !(chunked && name.eq_ignore_ascii_case("Content-Length")) {
+                response.add_header(name, value);
+            }
+        }
+        let sent = if req.method() == HttpMethod::Head {
+            stream.write_all(&response.head_bytes()).await
+        } else if chunked {
+            let body = ChunkedReader::new(upstream);
+            send_reader(stream, &req, self.config, &mut response, body).await
+        } else {
+            send_reader(stream, &req, self.config, &mut response, upstream).await
+        };
+        if let Err(e) = sent {
+            log::error!("Failed to send the response of {}: {}", server, e);
+        }
+    }
+}
 
-        stream.write_all(&response.as_bytes()).await.unwrap();
-        stream.flush().await.unwrap();
+impl Proxy<'_> {
+    // Send the request head to `pass`, either an address or an upstream group.
+    // In a group the other servers are tried when one cannot be reached.
+    async fn connect<'u>(
+        &self,
+        upstreams: &'u Upstreams,
+        req: &Request,
+        client: Option<SocketAddr>,
+    ) -> Result<
+        (
+            ReadHalf<Box<dyn Io>>,
+            WriteHalf<Box<dyn Io>>,
+            Option<Peer<'u>>,
+        ),
+        HttpStatus,
+    > {
+        let proxy = self.proxy;
+        let head = forward_head(req, proxy.upstream_host(), client);
+
+        let Some(group) = upstreams.group(&proxy.pass) else {
+            return match self.send(&Address::parse(&proxy.pass), &head).await {
+                Ok((reader, writer)) => Ok((reader, writer, None)),
+                Err((status, e)) => {
+                    log::error!("Failed to connect to {}: {}", proxy.pass, e);
+                    Err(status)
+                }
+            };
+        };
+        let key = group.hash_key(req, client.map(|client| client.ip()));
+        let mut tried = Vec::new();
+        let mut status = HttpStatus::BadGateway;
+        while let Some(peer) = group.pick(key.as_deref(), &tried) {
+            match self.send(peer.address(), &head).await {
+                Ok((reader, writer)) => return Ok((reader, writer, Some(peer))),
+                Err((failed, e)) => {
+                    log::error!("Failed to connect to {}: {}", peer.name(), e);
+                    peer.failed();
+                    status = failed;
+                    tried.push(peer.index);
+                }
+            }
+        }
+        if tried.is_empty() {
+            log::error!("No server of {} is available", proxy.pass);
+        }
+        Err(status)
+    }
+
+    // Connect to one server and send it the request head, failing with the
+    // status to answer and the reason
+    async fn send(
+        &self,
+        address: &Address,
+        head: &[u8],
+    ) -> Result<(ReadHalf<Box<dyn Io>>, WriteHalf<Box<dyn Io>>), (HttpStatus, String)> {
+        let connect_timeout = Duration::from_secs(self.proxy.connect_timeout);
+        match timeout(connect_timeout, open(address, head)).await {
+            Ok(Ok(upstream)) => Ok(upstream),
+            Ok(Err(e)) => Err((HttpStatus::BadGateway, e.to_string())),
+            Err(_) => Err((HttpStatus::GatewayTimeout, "timed out".to_string())),
+        }
+    }
+
+    fn error(&self, status: HttpStatus) -> HttpError<'_> {
+        HttpError {
+            status,
+            path_buf: self.path_buf,
+            config: self.config,
+        }
+    }
+}
+
+// The response a script writes to its output
+struct CgiOutput<'a> {
+    path_buf: &'a [u8],
+    config: &'a Config,
+    script: &'a Path,
+    // When the script is out of time
+    deadline: Instant,
+}
+
+// What became of a script's output
+enum Outcome {
+    Sent,
+    // Out of time, so the script is to be killed
+    TimedOut,
+    // To follow once the script is done and its process slot free
+    Redirect(String),
+}
+
+impl CgiOutput<'_> {
+    // Answer with the script's output, with a 504 if it runs out of time
+    // before anything was sent
+    async fn send(
+        &self,
+        stream: &mut TcpStream,
+        shared_data: Arc<Mutex<SharedData>>,
+        req: &Request,
+        output: impl AsyncRead + Send + Unpin,
+    ) -> Outcome {
+        let error = |status| HttpError {
+            status,
+            path_buf: self.path_buf,
+            config: self.config,
+        };
+        let script = self.script.display();
+        let mut output = BufReader::new(output);
+        let head = timeout_at(self.deadline, read_head(&mut output)).await;
+        let reply = match head.map(|head| head.map(|headers| headers.and_then(CgiReply::new))) {
+            Ok(Ok(Some(reply))) => reply,
+            Err(_) => {
+                log::error!("{} timed out", script);
+                error(HttpStatus::GatewayTimeout)
+                    .handle(stream, shared_data)
+                    .await;
+                return Outcome::TimedOut;
+            }
+            Ok(result) => {
+                if let Err(e) = result {
+                    log::error!("Failed to read the output of {}: {}", script, e);
+                } else {
+                    log::error!("{} sent no valid CGI header block", script);
+                }
+                error(HttpStatus::InternalServerError)
+                    .handle(stream, shared_data)
+                    .await;
+                return Outcome::Sent;
+            }
+        };
+        self.reply(stream, req, reply, output).await
+    }
+
+    // Answer with a parsed reply, then the rest of the output as its body
+    async fn reply(
+        &self,
+        stream: &mut TcpStream,
+        req: &Request,
+        reply: CgiReply,
+        output: impl AsyncRead + Send + Unpin,
+    ) -> Outcome {
+        let script = self.script.display();
+        match reply {
+            CgiReply::LocalRedirect(location) => return Outcome::Redirect(location),
+            CgiReply::Document { status, headers } => {
+                let mut response = Response::new();
+                response.set_status(status);
+                for (name, value) in headers {
+                    response.add_header(name, value);
+                }
+                let sent = send_reader(stream, req, self.config, &mut response, output);
+                match timeout_at(self.deadline, sent).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => log::error!("Failed to send the output of {}: {}", script, e),
+                    Err(_) => {
+                        // The response is cut short, the client sees the
+                        // connection close before the last chunk
+                        log::error!("{} timed out", script);
+                        return Outcome::TimedOut;
+                    }
+                }
+            }
+        }
+        Outcome::Sent
+    }
+}
+
+// Take one of the `max_processes` slots, waiting up to `queue_timeout`.
+// Fails with the status to answer when none frees up.
+async fn process_slot(
+    shared_data: &Arc<Mutex<SharedData>>,
+    config: &Config,
+) -> Result<Option<OwnedSemaphorePermit>, HttpStatus> {
+    let Some(slots) = shared_data.lock().unwrap().cgi_slots.clone() else {
+        return Ok(None);
+    };
+    let wait = Duration::from_secs(config.cgi.queue_timeout);
+    match timeout(wait, slots.acquire_owned()).await {
+        Ok(Ok(permit)) => Ok(Some(permit)),
+        _ => {
+            log::warn!("All {} script processes busy", config.cgi.max_processes);
+            Err(HttpStatus::ServiceUnavailable)
+        }
+    }
+}
+
+// Serve `location` as a GET request in place of a script, keeping the
+// original request headers
+async fn internal_redirect(
+    stream: &mut TcpStream,
+    shared_data: Arc<Mutex<SharedData>>,
+    config: &Config,
+    req: &Request,
+    location: &str,
+    redirects: u32,
+) {
+    let refused = if redirects >= MAX_REDIRECTS {
+        Some("too many redirects")
+    } else if location == req.path() {
+        Some("redirect to itself")
+    } else if has_dot_segment(location) {
+        Some("dot segments")
+    } else {
+        None
+    };
+    if let Some(reason) = refused {
+        log::error!(
+            "Refusing the redirect of {} to {}: {}",
+            req.path(),
+            location,
+            reason
+        );
+        let handler = HttpError {
+            status: HttpStatus::InternalServerError,
+            path_buf: &[],
+            config,
+        };
+        return handler.handle(stream, shared_data).await;
+    }
+    let mut head = format!("GET {} {}\r\n", location, req.version());
+    for (name, value) in req.headers() {
+        if !["Content-Length", "Content-Type", "Transfer-Encoding"]
+            .iter()
+            .any(|skip| name.eq_ignore_ascii_case(skip))
+        {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    head.push_str("\r\n");
+    let path_buf = head.into_bytes();
+
+    let redirected: Request = path_buf.clone().into();
+    let is_php = config.php && leads_to_script(redirected.uri_path(), is_php_script);
+    let uri_path = percent_decode(redirected.uri_path());
+    if let Some(proxy) = config.proxy(&uri_path) {
+        let handler = Proxy {
+            path_buf: &path_buf,
+            config,
+            proxy,
+        };
+        handler.handle(stream, shared_data).await;
+    } else if let Some(gateway) = config.gateway(&uri_path) {
+        let handler = Gateway {
+            path_buf: &path_buf,
+            config,
+            gateway,
+            redirects: redirects + 1,
+        };
+        handler.handle(stream, shared_data).await;
+    } else if config.cgi.handles(redirected.uri_path()) {
+        let handler = CgiFile {
+            path_buf: &path_buf,
+            config,
+            redirects: redirects + 1,
+        };
+        handler.handle(stream, shared_data).await;
+    } else if is_php {
+        let handler = PHPFile {
+            path_buf: &path_buf,
+            config,
+            redirects: redirects + 1,
+        };
+        handler.handle(stream, shared_data).await;
+    } else {
+        let handler = StaticFile {
+            path_buf: &path_buf,
+            config,
+            redirects: redirects + 1,
+        };
+        handler.handle(stream, shared_data).await;
     }
 }
 
@@ -82,6 +862,63 @@ impl Handler for VisitCount {
     }
 }
 
+#[async_trait]
+impl Handler for Status {
+    async fn handle(&self, stream: &mut TcpStream, shared_data: Arc<Mutex<SharedData>>) {
+        let (visit_count, file_cache, upstreams) = {
+            let shared_data = shared_data.lock().unwrap();
+            (
+                shared_data.visit_count,
+                shared_data.file_cache.stats(),
+                shared_data.upstreams.clone(),
+            )
+        };
+
+        let file_cache = match file_cache {
+            Some(stats) => format!(
+                "{{\"enabled\": true, \"hits\": {}, \"misses\": {}, \"evictions\": {}, \"entries\": {}, \"bytes\": {}}}",
+                stats.hits, stats.misses, stats.evictions, stats.entries, stats.bytes
+            ),
+            None => "{\"enabled\": false}".to_string(),
+        };
+        let upstreams: Vec<String> = upstreams
+            .stats()
+            .iter()
+            .map(|(name, servers)| {
+                let servers: Vec<String> = servers
+                    .iter()
+                    .map(|server| format!(
+                        "{{\"address\": \"{}\", \"weight\": {}, \"backup\": {}, \"state\": \"{}\", \"active\": {}, \"fails\": {}}}",
+                        json_escape(server.address), server.weight, server.backup, server.state, server.active, server.fails
+                    ))
+                    .collect();
+                format!(
+                    "{{\"name\": \"{}\", \"servers\": [{}]}}",
+                    json_escape(name),
+                    servers.join(", ")
+                )
+            })
+            .collect();
+        let body = format!(
+            "{{\"visit_count\": {}, \"file_cache\": {}, \"upstreams\": [{}]}}\n",
+            visit_count,
+            file_cache,
+            upstreams.join(", ")
+        );
+
+        let mut response = Response::new();
+        let response = response
+            .set_status(HttpStatus::Ok)
+            .set_headers("Content-Type".into(), "application/json".into())
+            .set_headers("Cache-Control".into(), "no-store".into())
+            .set_headers("Content-Length".into(), body.len().to_string())
+            .set_body(body.as_bytes());
+
+        stream.write_all(&response.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+}
+
 #[async_trait]
 impl Handler for Echo<'_> {
     async fn handle(&self, stream: &mut TcpStream, _shared_data: Arc<Mutex<SharedData>>) {
@@ -105,101 +942,703 @@ impl Handler for Echo<'_> {
 #[async_trait]
 impl Handler for StaticFile<'_> {
     async fn handle(&self, stream: &mut TcpStream, shared_data: Arc<Mutex<SharedData>>) {
-        let buf = &String::from_utf8_lossy(self.path_buf); // Buffer to string
-        let start_index = buf.find("GET ").unwrap() + 4;
-        let end_index = buf.find(" HTTP/").unwrap();
-        let mut path = String::from(&buf[start_index..end_index]);
-        if let Ok(metadata) = fs::metadata(format!(".{path}")).await {
-            if metadata.is_dir() {
-                let mut html = String::new();
-                html.push_str(
-                    format!("<html>\n<head>\n<title>{path}</title>\n</head>\n<body>\n").as_str(),
-                );
-                html.push_str("<h1>Directory Listing</h1>\n");
-                // Generate links for files
-                let mut dir_entries = tokio::fs::read_dir(format!(".{path}")).await.unwrap();
-                while let Some(entry) = dir_entries.next_entry().await.unwrap() {
-                    let file_name = entry.file_name();
-                    let file_path = entry.path();
-                    let file_path = file_path.to_string_lossy();
-                    let current_path_vec: Vec<&str> = file_path.split('/').collect();
-                    let current_path = current_path_vec.iter().rev().nth(1).unwrap();
-                    html.push_str(&format!(
-                        "<a href=\"./{}/{}\">{}</a><br>\n",
-                        current_path,
-                        file_name.to_string_lossy(),
-                        file_name.to_string_lossy()
-                    ));
-                }
-                html.push_str("</body>\n</html>");
-                let mut response = Response::new();
-                let response = response
-                    .set_status(HttpStatus::Ok)
-                    .set_headers("Content-Type".into(), ContentType::Html.to_string())
-                    .set_headers("Content-Length".into(), html.len().to_string())
-                    .set_body(&html.as_bytes());
+        let req: Request = self.path_buf.to_vec().into();
+        let uri_path = req.uri_path();
+        let path = match self.config.resolve(uri_path) {
+            Some(path) => path,
+            None => {
+                return self
+                    .error(HttpStatus::NotFound)
+                    .handle(stream, shared_data)
+                    .await
+            }
+        };
+        if self.config.access.denied(&percent_decode(uri_path)) {
+            return deny(self.config, self.path_buf, stream, shared_data).await;
+        }
+
+        match fs::metadata(&path).await {
+            Ok(metadata) if metadata.is_dir() => {
+                // Relative links inside the directory only work with a trailing slash
+                if !uri_path.ends_with('/') {
+                    let location = match req.query() {
+                        Some(query) => format!("{uri_path}/?{query}"),
+                        None => format!("{uri_path}/"),
+                    };
+                    return redirect(stream, &location).await;
+                }
+                if let Some(format) = req
+                    .query_param("archive")
+                    .as_deref()
+                    .and_then(ArchiveFormat::parse)
+                {
+                    return self
+                        .send_archive(stream, shared_data, &req, &path, format)
+                        .await;
+                }
+
+                match self.find_index(&path).await {
+                    Some(index) if is_php(&index) => {
+                        let handler = PHPFile {
+                            path_buf: self.path_buf,
+                            config: self.config,
+                            redirects: self.redirects,
+                        };
+                        let name = index.file_name().unwrap_or_default().to_string_lossy();
+                        let script_uri = format!("{}{}", uri_path, percent_encode(&name));
+                        match script_at(self.config, &script_uri, &index).await {
+                            Some(script) => handler.execute(stream, shared_data, &script).await,
+                            None => deny(self.config, self.path_buf, stream, shared_data).await,
+                        }
+                    }
+                    Some(index) => self.send_file(stream, shared_data, &req, &index).await,
+                    None if self.config.autoindex(&percent_decode(uri_path)) => {
+                        self.send_listing(stream, shared_data, &req, &path).await
+                    }
+                    None => deny(self.config, self.path_buf, stream, shared_data).await,
+                }
+            }
+            Ok(_) => self.send_file(stream, shared_data, &req, &path).await,
+            Err(_) if self.config.php => {
+                let handler = PHPFile {
+                    path_buf: self.path_buf,
+                    config: self.config,
+                    redirects: self.redirects,
+                };
+                handler.front_controller(stream, shared_data).await
+            }
+            Err(_) => {
+                self.error(HttpStatus::NotFound)
+                    .handle(stream, shared_data)
+                    .await
+            }
+        }
+    }
+}
+
+impl StaticFile<'_> {
+    fn error(&self, status: HttpStatus) -> HttpError<'_> {
+        HttpError {
+            status,
+            path_buf: self.path_buf,
+            config: self.config,
+        }
+    }
 
+    // The first configured index file present in `dir`.
+    // PHP index files are only considered when PHP is enabled.
+    async fn find_index(&self, dir: &Path) -> Option<PathBuf> {
+        for name in &self.config.index {
+            let candidate = dir.join(name);
+            if is_php(&candidate) && !self.config.php {
+                continue;
+            }
+            if let Ok(metadata) = fs::metadata(&candidate).await {
+                if metadata.is_file() {
+                    return Some(candidate);
+                }
+            }
+        }
+        None
+    }
+
+    // The best precompressed sibling (`app.js.br`, `app.js.gz`) the client
+    // accepts, and whether any sibling exists at all, since the response
+    // then varies on `Accept-Encoding` either way
+    async fn find_precompressed(
+        &self,
+        req: &Request,
+        path: &Path,
+    ) -> (Option<(PathBuf, Encoding)>, bool) {
+        let compression = &self.config.compression;
+        if !compression.precompressed {
+            return (None, false);
+        }
+
+        let mut available = Vec::new();
+        for encoding in &compression.encodings {
+            if let Some(ext) = encoding.file_extension() {
+                let mut sibling = path.as_os_str().to_owned();
+                sibling.push(format!(".{ext}"));
+                let sibling = PathBuf::from(sibling);
+                if fs::metadata(&sibling).await.is_ok_and(|m| m.is_file()) {
+                    available.push((sibling, *encoding));
+                }
+            }
+        }
+
+        let encodings: Vec<Encoding> = available.iter().map(|(_, encoding)| *encoding).collect();
+        let chosen = negotiate(req.header("Accept-Encoding"), &encodings);
+        let has_siblings = !available.is_empty();
+        let sibling = available
+            .into_iter()
+            .find(|(_, encoding)| *encoding == chosen);
+        (sibling, has_siblings)
+    }
+
+    async fn send_file(
+        &self,
+        stream: &mut TcpStream,
+        shared_data: Arc<Mutex<SharedData>>,
+        req: &Request,
+        path: &Path,
+    ) {
+        let (precompressed, has_siblings) = self.find_precompressed(req, path).await;
+        let file_path = match &precompressed {
+            Some((sibling, _)) => sibling.as_path(),
+            None => path,
+        };
+        let file_cache = shared_data.lock().unwrap().file_cache.clone();
+        let file = match file_cache
+            .open(file_path, self.config.stream_min_size)
+            .await
+        {
+            Ok(file) => file,
+            Err(_) => {
+                return self
+                    .error(HttpStatus::NotFound)
+                    .handle(stream, shared_data)
+                    .await
+            }
+        };
+        let metadata = &file.metadata;
+        let len = metadata.len();
+        // Streamed files are only sniffed by their first bytes and never
+        // hashed, so they keep the metadata tag in `content` mode.
+        // Cached files are borrowed, not copied.
+        let streamed_head;
+        let (head, etag_mode): (&[u8], _) = match &file.body {
+            FileBody::Memory(content) => (&content[..], self.config.etag),
+            FileBody::Stream(stream_file) => {
+                streamed_head =
+                    match read_head_bytes(stream_file, SNIFF_LEN.min(len as usize)).await {
+                        Ok(head) => head,
+                        Err(e) => {
+                            log::error!("Failed to read {}: {}", path.display(), e);
+                            return self
+                                .error(HttpStatus::InternalServerError)
+                                .handle(stream, shared_data)
+                                .await;
+                        }
+                    };
+                let mode = match self.config.etag {
+                    EtagMode::Content => EtagMode::Strong,
+                    mode => mode,
+                };
+                (&streamed_head[..], mode)
+            }
+        };
+        // Sniffing compressed bytes would only ever find gzip
+        let content_type = match precompressed {
+            Some(_) => self.config.content_type(path, &[]),
+            None => self.config.content_type(path, head),
+        };
+        let modified = metadata.modified().ok();
+        let mut etag = make_etag(etag_mode, len, modified, inode(metadata), head);
+        if let (Some(tag), Some((_, encoding))) = (&etag, &precompressed) {
+            etag = Some(format!("{}-{}\"", tag.trim_end_matches('"'), encoding));
+        }
+        let validators = Validators {
+            etag,
+            last_modified: modified,
+        };
+
+        let mut response = Response::new();
+        if let Some((_, encoding)) = &precompressed {
+            response.set_headers("Content-Encoding".into(), encoding.to_string());
+        }
+        if has_siblings {
+            response.add_vary("Accept-Encoding");
+        }
+        if let Some(etag) = &validators.etag {
+            response.set_headers("ETag".into(), etag.clone());
+        }
+        if let Some(modified) = modified {
+            response.set_headers("Last-Modified".into(), fmt_http_date(modified));
+        }
+
+        let rule = self
+            .config
+            .cache_rule(&percent_decode(req.uri_path()), path);
+        apply_cache_rule(&mut response, rule);
+
+        match evaluate(req, &validators) {
+            Precondition::Proceed => {}
+            Precondition::NotModified => {
+                let compression = &self.config.compression;
+                if compression.enabled && compression.compressible(&content_type) {
+                    response.add_vary("Accept-Encoding");
+                }
+                response.set_status(HttpStatus::NotModified);
                 stream.write_all(&response.as_bytes()).await.unwrap();
                 stream.flush().await.unwrap();
-            } else {
-                path = format!(".{}", path);
-                //println!("{}", path);
-                //println!("Get: {}", path);
-                let file = fs::read(&path).await;
-                if let Ok(f) = file {
-                    let content_type = parse_content_type(&path);
-
-                    let mut response = Response::new();
-                    let response = response
-                        .set_status(HttpStatus::Ok)
-                        .set_headers("Content-Type".into(), content_type.to_string())
-                        .set_headers("Content-Length".into(), f.len().to_string())
-                        .set_body(&f);
-
-                    stream.write_all(&response.as_bytes()).await.unwrap();
-                    stream.flush().await.unwrap();
-                } else {
-                    NotFound.handle(stream, Arc::clone(&shared_data)).await;
+                return;
+            }
+            Precondition::Failed => {
+                response
+                    .set_status(HttpStatus::PreconditionFailed)
+                    .set_headers("Content-Length".into(), "0".into());
+                stream.write_all(&response.as_bytes()).await.unwrap();
+                stream.flush().await.unwrap();
+                return;
+            }
+        }
+
+        let ranges = match req.header("Range") {
+            Some(range)
+                if req
+                    .header("If-Range")
+                    .is_none_or(|if_range| if_range_matches(if_range, &validators)) =>
+            {
+                parse_range(range, len)
+            }
+            _ => RangeRequest::Full,
+        };
+
+        response.set_headers("Accept-Ranges".into(), "bytes".into());
+        if !self.config.sniff {
+            response.set_headers("X-Content-Type-Options".into(), "nosniff".into());
+        }
+
+        let result = match (&file.body, &ranges) {
+            (_, RangeRequest::Unsatisfiable) => {
+                let response = response
+                    .set_status(HttpStatus::RangeNotSatisfiable)
+                    .set_headers("Content-Range".into(), format!("bytes */{len}"))
+                    .set_headers("Content-Length".into(), "0".into());
+                send(stream, req, self.config, response).await
+            }
+            (FileBody::Memory(content), _) => {
+                let multipart;
+                let body: &[u8] = match &ranges {
+                    RangeRequest::Partial(ranges) if ranges.len() == 1 => {
+                        let range = &ranges[0];
+                        response
+                            .set_status(HttpStatus::PartialContent)
+                            .set_headers("Content-Type".into(), content_type)
+                            .set_headers("Content-Range".into(), content_range(range, len));
+                        &content[range.start as usize..range.end as usize]
+                    }
+                    RangeRequest::Partial(ranges) => {
+                        let boundary = boundary();
+                        multipart = multipart_body(content, ranges, &content_type, &boundary);
+                        response.set_status(HttpStatus::PartialContent).set_headers(
+                            "Content-Type".into(),
+                            format!("multipart/byteranges; boundary={boundary}"),
+                        );
+                        &multipart
+                    }
+                    _ => {
+                        response
+                            .set_status(HttpStatus::Ok)
+                            .set_headers("Content-Type".into(), content_type);
+                        content
+                    }
+                };
+                let response = response
+                    .set_headers("Content-Length".into(), body.len().to_string())
+                    .set_body(body);
+                send(stream, req, self.config, response).await
+            }
+            (FileBody::Stream(file), RangeRequest::Partial(ranges)) if ranges.len() > 1 => {
+                let boundary = boundary();
+                let parts: Vec<_> = ranges
+                    .iter()
+                    .map(|range| {
+                        let header = multipart_part_header(range, len, &content_type, &boundary);
+                        (header, range.clone())
+                    })
+                    .collect();
+                let body_len = multipart_length(ranges, len, &content_type, &boundary);
+                response
+                    .set_status(HttpStatus::PartialContent)
+                    .set_headers(
+                        "Content-Type".into(),
+                        format!("multipart/byteranges; boundary={boundary}"),
+                    )
+                    .set_headers("Content-Length".into(), body_len.to_string());
+                let trailer = multipart_trailer(&boundary);
+                send_multipart(stream, req, self.config, &response, file, &parts, &trailer).await
+            }
+            (FileBody::Stream(file), _) => {
+                let range = match &ranges {
+                    RangeRequest::Partial(ranges) => {
+                        response
+                            .set_status(HttpStatus::PartialContent)
+                            .set_headers("Content-Range".into(), content_range(&ranges[0], len));
+                        ranges[0].clone()
+                    }
+                    _ => {
+                        response.set_status(HttpStatus::Ok);
+                        0..len
+                    }
+                };
+                response
+                    .set_headers("Content-Type".into(), content_type)
+                    .set_headers(
+                        "Content-Length".into(),
+                        (range.end - range.start).to_string(),
+                    );
+                send_stream(stream, req, self.config, &mut response, file, range).await
+            }
+        };
+
+        if let Err(e) = result {
+            log::error!("Failed to send {}: {}", path.display(), e);
+        }
+    }
+
+    // Stream the tree below `dir` as an archive, built while it is sent
+    async fn send_archive(
+        &self,
+        stream: &mut TcpStream,
+        shared_data: Arc<Mutex<SharedData>>,
+        req: &Request,
+        dir: &Path,
+        format: ArchiveFormat,
+    ) {
+        let uri_path = percent_decode(req.uri_path());
+        if !self.config.archive.enabled || !self.config.autoindex(&uri_path) {
+            return deny(self.config, self.path_buf, stream, shared_data).await;
+        }
+        let entries = match collect(dir, &uri_path, &self.config.access).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("Failed to archive {}: {}", dir.display(), e);
+                return self
+                    .error(HttpStatus::NotFound)
+                    .handle(stream, shared_data)
+                    .await;
+            }
+        };
+        let total: u64 = entries.iter().map(|entry| entry.size).sum();
+        if total > self.config.archive.max_size {
+            log::warn!(
+                "Refused to archive {}: {} bytes is over the limit",
+                dir.display(),
+                total
+            );
+            return self
+                .error(HttpStatus::Forbidden)
+                .handle(stream, shared_data)
+                .await;
+        }
+
+        let name = match uri_path.trim_end_matches('/').rsplit('/').next() {
+            Some(name) if !name.is_empty() => name,
+            _ => "root",
+        };
+        let file_name = format!("{name}.{}", format.extension());
+        // Plain `filename` for old clients, `filename*` keeps non-ASCII names
+        let fallback: String = file_name
+            .chars()
+            .map(|c| match c {
+                ' '..='~' if c != '"' && c != '\\' => c,
+                _ => '_',
+            })
+            .collect();
+        let mut response = Response::new();
+        response
+            .set_status(HttpStatus::Ok)
+            .set_headers("Content-Type".into(), format.content_type().into())
+            .set_headers(
+                "Content-Disposition".into(),
+                format!(
+                    "attachment; filename=\"{fallback}\"; filename*=UTF-8''{}",
+                    percent_encode(&file_name)
+                ),
+            )
+            .set_headers("Cache-Control".into(), "no-store".into())
+            .set_headers("Transfer-Encoding".into(), "chunked".into());
+
+        let result = match stream.write_all(&response.head_bytes()).await {
+            Ok(()) if req.method() == HttpMethod::Head => stream.flush().await,
+            Ok(()) => {
+                let level = self.config.compression.level;
+                write_archive(stream, format, &entries, level).await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            log::error!("Failed to send archive of {}: {}", dir.display(), e);
+        }
+    }
+
+    async fn send_listing(
+        &self,
+        stream: &mut TcpStream,
+        shared_data: Arc<Mutex<SharedData>>,
+        req: &Request,
+        dir: &Path,
+    ) {
+        let uri_path = percent_decode(req.uri_path());
+        let mut entries = match read_entries(dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("Failed to list {}: {}", dir.display(), e);
+                return self
+                    .error(HttpStatus::NotFound)
+                    .handle(stream, shared_data)
+                    .await;
+            }
+        };
+        entries.retain(|entry| {
+            !self
+                .config
+                .access
+                .denied(&format!("{uri_path}{}", entry.name))
+        });
+        let key = SortKey::parse(req.query_param("sort").as_deref());
+        let order = Order::parse(req.query_param("order").as_deref());
+        sort_entries(&mut entries, key, order);
+
+        // The configured format is offered first so it wins `*/*`
+        let default = self.config.autoindex_format;
+        let offered: Vec<&str> = std::iter::once(default)
+            .chain(Format::ALL.into_iter().filter(|format| *format != default))
+            .map(|format| format.mime())
+            .collect();
+        let format = negotiate_type(req.header("Accept"), &offered)
+            .and_then(Format::from_mime)
+            .unwrap_or(default);
+        let (body, content_type) = match format {
+            Format::Html => (
+                render_html(&uri_path, &entries, key, order),
+                ContentType::Html.to_string(),
+            ),
+            Format::Json | Format::Xml => {
+                for entry in entries.iter_mut().filter(|entry| !entry.is_dir) {
+                    entry.mime = Some(self.config.mime_type(&dir.join(&entry.name)).into());
                 }
+                let body = if format == Format::Json {
+                    render_json(&entries)
+                } else {
+                    render_xml(&entries)
+                };
+                (body, with_charset(format.mime()))
             }
-        } else {
-            NotFound.handle(stream, Arc::clone(&shared_data)).await;
+        };
+
+        let mut response = Response::new();
+        let rule = self.config.cache_rule(&uri_path, dir);
+        apply_cache_rule(&mut response, rule);
+        let response = response
+            .set_status(HttpStatus::Ok)
+            .set_headers("Content-Type".into(), content_type)
+            .set_headers("Content-Length".into(), body.len().to_string())
+            .add_vary("Accept")
+            .set_body(body.as_bytes());
+
+        if let Err(e) = send(stream, req, self.config, response).await {
+            log::error!("Failed to send listing of {}: {}", dir.display(), e);
         }
     }
 }
 
-#[async_trait]
-impl Handler for NotFound {
-    async fn handle(&self, stream: &mut TcpStream, _shared_data: Arc<Mutex<SharedData>>) {
-        let file = fs::read("./static/404.html").await;
-        if let Ok(f) = file {
-            let mut response = Response::new();
-            let response = response
-                .set_status(HttpStatus::NotFound)
-                .set_headers("Content-Type".into(), ContentType::Html.to_string())
-                .set_headers("Content-Length".into(), f.len().to_string())
-                .set_body(&f);
-
-            stream.write_all(&response.as_bytes()).await.unwrap();
-            stream.flush().await.unwrap();
-        }
-    }
-}
-
-// Parse the `Content-Type` from request
-fn parse_content_type(req: &str) -> ContentType {
-    // .html or .htm
-    if req.contains(".htm") {
-        ContentType::Html
-    } else if req.contains(".txt") {
-        ContentType::PlainText
-    } else if req.contains(".css") {
-        ContentType::Css
-    } else if req.contains(".png") || req.contains(".jpg") || req.contains(".ico") {
-        ContentType::AvifImage
-    } else if req.contains(".mp4") {
-        ContentType::Mp4Video
+// Refuse a hidden path or a disabled listing with the configured status
+async fn deny(
+    config: &Config,
+    path_buf: &[u8],
+    stream: &mut TcpStream,
+    shared_data: Arc<Mutex<SharedData>>,
+) {
+    let status = if config.access.deny_status == 403 {
+        HttpStatus::Forbidden
     } else {
-        ContentType::Html
+        HttpStatus::NotFound
+    };
+    let handler = HttpError {
+        status,
+        path_buf,
+        config,
+    };
+    handler.handle(stream, shared_data).await
+}
+
+async fn redirect(stream: &mut TcpStream, location: &str) {
+    let mut response = Response::new();
+    let response = response
+        .set_status(HttpStatus::MovedPermanently)
+        .set_headers("Location".into(), location.to_string())
+        .set_headers("Content-Length".into(), "0".into());
+
+    stream.write_all(&response.as_bytes()).await.unwrap();
+    stream.flush().await.unwrap();
+}
+
+// Add `Cache-Control`, `Expires` and `Vary` from the matching cache rule
+fn apply_cache_rule(response: &mut Response, rule: Option<&CacheRule>) {
+    let rule = match rule {
+        Some(rule) => rule,
+        None => return,
+    };
+    if let Some(cache_control) = &rule.cache_control {
+        response.set_headers("Cache-Control".into(), cache_control.clone());
+    }
+    if let Some(expires) = rule.expires() {
+        let now = SystemTime::now();
+        let offset = Duration::from_secs(expires.unsigned_abs());
+        let expires = if expires >= 0 {
+            now.checked_add(offset)
+        } else {
+            now.checked_sub(offset)
+        };
+        if let Some(expires) = expires {
+            response.set_headers("Expires".into(), fmt_http_date(expires));
+        }
+    }
+    // Added to, the response may already vary on `Accept-Encoding`
+    for field in &rule.vary {
+        response.add_vary(field);
+    }
+}
+
+// The first `len` bytes of a file, read on the blocking pool
+async fn read_head_bytes(file: &std::fs::File, len: usize) -> io::Result<Vec<u8>> {
+    let file = file.try_clone()?;
+    tokio::task::spawn_blocking(move || {
+        let mut head = Vec::with_capacity(len);
+        (&file).take(len as u64).read_to_end(&mut head)?;
+        Ok(head)
+    })
+    .await?
+}
+
+#[cfg(unix)]
+fn inode(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
+
+fn is_php(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "php")
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use tokio::sync::Semaphore;
+
+    use crate::{
+        config::{CacheRule, Config},
+        server::cgi::CgiConfig,
+        SharedData,
+    };
+
+    use super::{CgiFile, Handler, StaticFile};
+
+    // The response of `handler` to one request
+    async fn respond(handler: &(dyn Handler + Sync), shared_data: SharedData) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        handler
+            .handle(&mut server, Arc::new(Mutex::new(shared_data)))
+            .await;
+        drop(server);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        String::from_utf8_lossy(&response).into_owned()
+    }
+
+    #[tokio::test]
+    async fn test_vary_with_precompressed() {
+        let root = std::env::temp_dir().join(format!("minginx-vary-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("app.js"), "let a = 1;").unwrap();
+        std::fs::write(root.join("app.js.gz"), "gzipped").unwrap();
+        let config = Config {
+            root: root.clone(),
+            cache_rules: vec![CacheRule {
+                extensions: vec!["js".into()],
+                vary: vec!["Origin".into(), "accept-encoding".into()],
+                ..CacheRule::default()
+            }],
+            ..Config::default()
+        };
+
+        for accept in ["gzip", "identity"] {
+            let request = format!("GET /app.js HTTP/1.1\r\nAccept-Encoding: {accept}\r\n\r\n");
+            let handler = StaticFile {
+                path_buf: request.as_bytes(),
+                config: &config,
+                redirects: 0,
+            };
+            let response = respond(&handler, SharedData::default()).await;
+            assert!(response.contains("\r\nVary: Accept-Encoding, Origin\r\n"));
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_head() {
+        let root = std::env::temp_dir().join(format!("minginx-head-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.txt"), "hello").unwrap();
+        let config = Config {
+            root: root.clone(),
+            ..Config::default()
+        };
+        let handler = StaticFile {
+            path_buf: b"HEAD /a.txt HTTP/1.1\r\n\r\n",
+            config: &config,
+            redirects: 0,
+        };
+        let response = respond(&handler, SharedData::default()).await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\nContent-Length: 5\r\n"));
+        assert!(response.ends_with("\r\n\r\n"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_redirect_loop() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = std::env::temp_dir().join(format!("minginx-loop-{}", std::process::id()));
+        let dir = root.join("cgi-bin");
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, target) in [("a.sh", "b.sh"), ("b.sh", "a.sh")] {
+            let script = format!("#!/bin/sh\nprintf 'Location: /cgi-bin/{target}\\r\\n\\r\\n'\n");
+            let path = dir.join(name);
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let config = Config {
+            root: root.clone(),
+            cgi: CgiConfig {
+                dirs: vec!["/cgi-bin/".into()],
+                max_processes: 1,
+                ..CgiConfig::default()
+            },
+            ..Config::default()
+        };
+        let handler = CgiFile {
+            path_buf: b"GET /cgi-bin/a.sh HTTP/1.1\r\n\r\n",
+            config: &config,
+            redirects: 0,
+        };
+        // Each script frees its only process slot before the next one runs
+        let shared_data = SharedData {
+            cgi_slots: Some(Arc::new(Semaphore::new(1))),
+            ..SharedData::default()
+        };
+        let response = respond(&handler, shared_data).await;
+        assert!(response.starts_with("HTTP/1.1 500 "));
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
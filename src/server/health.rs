@@ -0,0 +1,283 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+use tokio::{
+    io::BufReader,
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+use super::{
+    balancer::Upstreams,
+    proxy::read_response_head,
+    upstream::{send_request, Address},
+};
+
+// Periodic requests telling whether the servers of a group are healthy
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HealthCheckConfig {
+    pub path: String,
+    // `Host` sent with checks, the server's address by default
+    pub host: Option<String>,
+    // Seconds between checks
+    pub interval: u64,
+    // Seconds to wait for the response head
+    pub timeout: u64,
+    // Expected statuses, any 2xx or 3xx when empty
+    pub status: Vec<u16>,
+    // Consecutive failed checks to mark a server down
+    pub fails: u32,
+    // Consecutive passed checks to bring it back
+    pub passes: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            path: "/".into(),
+            host: None,
+            interval: 5,
+            timeout: 2,
+            status: Vec::new(),
+            fails: 1,
+            passes: 1,
+        }
+    }
+}
+
+impl HealthCheckConfig {
+    fn expects(&self, code: u16) -> bool {
+        if self.status.is_empty() {
+            (200..400).contains(&code)
+        } else {
+            self.status.contains(&code)
+        }
+    }
+}
+
+// Health of one server, from the requests sent to it and from checks
+#[derive(Debug, Default)]
+pub struct Health {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Consecutive failed requests
+    fails: u32,
+    // Not chosen until then after `max_fails` failed requests
+    down_until: Option<Instant>,
+    // Failed its health checks and not yet recovered
+    unhealthy: bool,
+    check_fails: u32,
+    check_passes: u32,
+}
+
+impl Health {
+    pub fn is_up(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.unhealthy && state.down_until.is_none_or(|until| now >= until)
+    }
+
+    // `up`, `down` after failed requests or `unhealthy` after failed checks
+    pub fn state(&self, now: Instant) -> &'static str {
+        let state = self.state.lock().unwrap();
+        if state.unhealthy {
+            "unhealthy"
+        } else if state.down_until.is_some_and(|until| now < until) {
+            "down"
+        } else {
+            "up"
+        }
+    }
+
+    pub fn fails(&self) -> u32 {
+        self.state.lock().unwrap().fails
+    }
+
+    pub fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.fails = 0;
+        state.down_until = None;
+    }
+
+    // Record a failed request. Returns `true` when the server goes down.
+    pub fn failed(&self, now: Instant, max_fails: u32, fail_timeout: Duration) -> bool {
+        if max_fails == 0 {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.fails += 1;
+        if state.fails < max_fails {
+            return false;
+        }
+        let was_up = state.down_until.is_none_or(|until| now >= until);
+        state.down_until = Some(now + fail_timeout);
+        was_up
+    }
+
+    // Record a health check. Returns the new state when it changes.
+    pub fn checked(&self, passed: bool, config: &HealthCheckConfig) -> Option<&'static str> {
+        let mut state = self.state.lock().unwrap();
+        if passed {
+            state.check_fails = 0;
+            state.check_passes += 1;
+            if state.unhealthy && state.check_passes >= config.passes {
+                // A healthy server also gets a clean slate for requests
+                *state = State::default();
+                return Some("up");
+            }
+        } else {
+            state.check_passes = 0;
+            state.check_fails += 1;
+            if !state.unhealthy && state.check_fails >= config.fails {
+                state.unhealthy = true;
+                return Some("unhealthy");
+            }
+        }
+        None
+    }
+}
+
+// Check every server of the groups with a `health_check`, each on its own
+pub fn spawn_health_checks(upstreams: &Arc<Upstreams>) {
+    for (group_index, group) in upstreams.groups().iter().enumerate() {
+        let Some(check) = group.health_check() else {
+            continue;
+        };
+        for index in 0..group.addresses().len() {
+            let upstreams = upstreams.clone();
+            let check = check.clone();
+            tokio::spawn(async move {
+                let group = &upstreams.groups()[group_index];
+                let address = &group.addresses()[index];
+                let mut ticker = interval(Duration::from_secs(check.interval.max(1)));
+                ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                loop {
+                    ticker.tick().await;
+                    let result = probe(address, &check).await;
+                    let passed = result.is_ok();
+                    match (group.health(index).checked(passed, &check), result) {
+                        (Some(state), Err(reason)) => log::warn!(
+                            "{} in {} is {}: {}",
+                            group.server_name(index),
+                            group.name(),
+                            state,
+                            reason
+                        ),
+                        (Some(state), Ok(())) => log::warn!(
+                            "{} in {} is {}",
+                            group.server_name(index),
+                            group.name(),
+                            state
+                        ),
+                        (None, _) => {}
+                    }
+                }
+            });
+        }
+    }
+}
+
+// Send one check, failing with the reason
+async fn probe(address: &Address, check: &HealthCheckConfig) -> Result<(), String> {
+    let host = check.host.as_deref().unwrap_or(address.host());
+    let head = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: minginx\r\nConnection: close\r\n\r\n",
+        check.path, host
+    );
+    let response = async {
+        let reader = send_request(address, head.as_bytes(), Vec::new()).await?;
+        read_response_head(&mut BufReader::new(reader)).await
+    };
+    match timeout(Duration::from_secs(check.timeout), response).await {
+        Ok(Ok(Some((status, _)))) if check.expects(status.code()) => Ok(()),
+        Ok(Ok(Some((status, _)))) => Err(format!("status {}", status.code())),
+        Ok(Ok(None)) => Err("invalid response".into()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::server::upstream::Address;
+
+    use super::{probe, Health, HealthCheckConfig};
+
+    #[test]
+    fn test_passive_health() {
+        let health = Health::default();
+        let now = Instant::now();
+        let fail_timeout = Duration::from_secs(10);
+        assert!(!health.failed(now, 2, fail_timeout));
+        health.succeeded();
+        assert!(!health.failed(now, 2, fail_timeout));
+        assert!(health.is_up(now));
+        // The second failure in a row takes it down for `fail_timeout`
+        assert!(health.failed(now, 2, fail_timeout));
+        assert!(!health.is_up(now));
+        assert_eq!("down", health.state(now));
+        let later = now + fail_timeout;
+        assert!(health.is_up(later));
+        // Still failing after the timeout, so down again at once
+        assert!(health.failed(later, 2, fail_timeout));
+        assert!(!health.is_up(later));
+        health.succeeded();
+        assert!(health.is_up(later));
+        assert!(!health.failed(later, 0, fail_timeout));
+    }
+
+    #[test]
+    fn test_active_health() {
+        let health = Health::default();
+        let config = HealthCheckConfig {
+            fails: 2,
+            passes: 2,
+            ..HealthCheckConfig::default()
+        };
+        let now = Instant::now();
+        assert_eq!(None, health.checked(false, &config));
+        assert_eq!(None, health.checked(true, &config));
+        assert_eq!(None, health.checked(false, &config));
+        assert_eq!(Some("unhealthy"), health.checked(false, &config));
+        assert!(!health.is_up(now));
+        assert_eq!(None, health.checked(true, &config));
+        assert_eq!(Some("up"), health.checked(true, &config));
+        assert!(health.is_up(now));
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address::Tcp(listener.local_addr().unwrap().to_string());
+        tokio::spawn(async move {
+            for status in ["204 No Content", "503 Service Unavailable"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 1024];
+                let read = stream.read(&mut request).await.unwrap();
+                assert!(request[..read].starts_with(b"GET /healthz HTTP/1.1\r\n"));
+                let response = format!("HTTP/1.1 {status}\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        let config = HealthCheckConfig {
+            path: "/healthz".into(),
+            ..HealthCheckConfig::default()
+        };
+        assert_eq!(Ok(()), probe(&address, &config).await);
+        assert_eq!(Err("status 503".into()), probe(&address, &config).await);
+        let closed = Address::Tcp("127.0.0.1:1".into());
+        assert!(probe(&closed, &config).await.is_err());
+    }
+}
@@ -1 +1,15 @@
+pub mod archive;
+pub mod autoindex;
+pub mod balancer;
+pub mod cache;
+pub mod cgi;
+pub mod error;
+pub mod fastcgi;
+pub mod gateway;
 pub mod handlers;
+pub mod health;
+pub mod proxy;
+pub mod reader;
+pub mod send;
+pub mod sendfile;
+pub mod upstream;
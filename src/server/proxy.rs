@@ -0,0 +1,297 @@
+use std::{io, net::SocketAddr};
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::http::{request::Request, response::HttpStatus};
+
+use super::{cgi::read_head, reader::body_length, upstream::Address};
+
+// Requests under `prefix` forwarded to an HTTP/1.1 server
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProxyConfig {
+    pub prefix: String,
+    // `127.0.0.1:8000` or `unix:/run/app.sock`
+    pub pass: String,
+    // `Host` sent upstream, the address of `pass` by default
+    pub host: Option<String>,
+    // Seconds to wait for the connection
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: u64,
+    // Seconds to wait for the response head after the request body
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // Largest request body passed on, unlimited by default
+    pub client_max_body_size: Option<u64>,
+}
+
+fn default_connect_timeout() -> u64 {
+    10
+}
+
+fn default_timeout() -> u64 {
+    60
+}
+
+impl ProxyConfig {
+    // Value of the `Host` header sent upstream
+    pub fn upstream_host(&self) -> &str {
+        match (&self.host, Address::parse(&self.pass)) {
+            (Some(host), _) => host,
+            (None, Address::Tcp(_)) => &self.pass,
+            (None, Address::Unix(_)) => "localhost",
+        }
+    }
+}
+
+// Headers that only apply to a single connection (RFC 9110, section 7.6.1)
+const HOP_BY_HOP: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+// `headers` without the hop-by-hop ones, including those `Connection` names
+pub fn end_to_end(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let listed: Vec<&str> = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(str::trim)
+        .collect();
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            !HOP_BY_HOP
+                .iter()
+                .chain(&listed)
+                .any(|hop| name.eq_ignore_ascii_case(hop))
+        })
+        .cloned()
+        .collect()
+}
+
+// The request head sent upstream: hop-by-hop headers removed, `Host`
+// rewritten and the client recorded in `X-Forwarded-*` and `Forwarded`
+pub fn forward_head(req: &Request, host: &str, client: Option<SocketAddr>) -> Vec<u8> {
+    let original_host = req.header("Host");
+    let joined = |name: &str| {
+        let values: Vec<&str> = req
+            .headers()
+            .iter()
+            .filter(|(other, _)| other.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect();
+        values.join(", ")
+    };
+    let replaced = [
+        "Host",
+        "X-Forwarded-For",
+        "X-Forwarded-Proto",
+        "X-Forwarded-Host",
+        "Forwarded",
+        // Answered before the body is read
+        "Expect",
+        // A chunked body is sent on chunked again
+        "Content-Length",
+    ];
+
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method(), req.path());
+    head.push_str(&format!("Host: {host}\r\n"));
+    for (name, value) in end_to_end(req.headers()) {
+        if !replaced
+            .iter()
+            .any(|other| name.eq_ignore_ascii_case(other))
+        {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    match body_length(req) {
+        Ok(Some(length)) if length > 0 || req.header("Content-Length").is_some() => {
+            head.push_str(&format!("Content-Length: {length}\r\n"))
+        }
+        Ok(None) => head.push_str("Transfer-Encoding: chunked\r\n"),
+        _ => {}
+    }
+
+    let mut forwarded_for = joined("X-Forwarded-For");
+    let mut forwarded = joined("Forwarded");
+    let mut element = Vec::new();
+    if let Some(client) = client {
+        let ip = client.ip().to_string();
+        if !forwarded_for.is_empty() {
+            forwarded_for.push_str(", ");
+        }
+        forwarded_for.push_str(&ip);
+        element.push(match client {
+            SocketAddr::V4(_) => format!("for={ip}"),
+            SocketAddr::V6(_) => format!("for=\"[{ip}]\""),
+        });
+    }
+    if let Some(original_host) = original_host {
+        element.push(format!("host={}", quote(original_host)));
+    }
+    element.push("proto=http".into());
+    if !forwarded.is_empty() {
+        forwarded.push_str(", ");
+    }
+    forwarded.push_str(&element.join(";"));
+
+    if !forwarded_for.is_empty() {
+        head.push_str(&format!("X-Forwarded-For: {forwarded_for}\r\n"));
+    }
+    head.push_str("X-Forwarded-Proto: http\r\n");
+    if let Some(original_host) = original_host {
+        head.push_str(&format!("X-Forwarded-Host: {original_host}\r\n"));
+    }
+    head.push_str(&format!("Forwarded: {forwarded}\r\n"));
+    // One request per connection, the response ends when it closes
+    head.push_str("Connection: close\r\n\r\n");
+    head.into_bytes()
+}
+
+// A `Forwarded` value, quoted unless it is a plain token
+fn quote(value: &str) -> String {
+    let token = value
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace(['"', '\\'], ""))
+    }
+}
+
+// Read the status line and header fields of the final response, skipping
+// interim 1xx ones. `None` when the head is malformed.
+pub async fn read_response_head(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<Option<(HttpStatus, Vec<(String, String)>)>> {
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        let status = line
+            .strip_prefix("HTTP/1.")
+            .and_then(|rest| rest.split_whitespace().nth(1))
+            .and_then(|code| code.parse::<u16>().ok())
+            .and_then(HttpStatus::from_code);
+        let Some(status) = status else {
+            return Ok(None);
+        };
+        let Some(headers) = read_head(reader).await? else {
+            return Ok(None);
+        };
+        match status.code() {
+            // 101 Switching Protocols is final, but upgrades are not forwarded
+            101 => return Ok(None),
+            100..=199 => {}
+            _ => return Ok(Some((status, headers))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::BufReader;
+
+    use crate::http::{request::Request, response::HttpStatus};
+
+    use super::{end_to_end, forward_head, read_response_head, ProxyConfig};
+
+    #[test]
+    fn test_end_to_end() {
+        let headers: Vec<(String, String)> = [
+            ("Connection", "keep-alive, X-Secret"),
+            ("Keep-Alive", "timeout=5"),
+            ("x-secret", "1"),
+            ("Transfer-Encoding", "chunked"),
+            ("Content-Type", "text/plain"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        assert_eq!(
+            vec![("Content-Type".to_string(), "text/plain".to_string())],
+            end_to_end(&headers)
+        );
+    }
+
+    #[test]
+    fn test_forward_head() {
+        let req: Request = b"POST /api/users?page=2 HTTP/1.1\r\n\
+            Host: example.com:5000\r\n\
+            Connection: keep-alive\r\n\
+            X-Forwarded-For: 203.0.113.7\r\n\
+            Expect: 100-continue\r\n\
+            Content-Length: 2\r\n\r\nhi"
+            .to_vec()
+            .into();
+        let head = forward_head(&req, "127.0.0.1:8000", Some("[::1]:40000".parse().unwrap()));
+        assert_eq!(
+            "POST /api/users?page=2 HTTP/1.1\r\n\
+            Host: 127.0.0.1:8000\r\n\
+            Content-Length: 2\r\n\
+            X-Forwarded-For: 203.0.113.7, ::1\r\n\
+            X-Forwarded-Proto: http\r\n\
+            X-Forwarded-Host: example.com:5000\r\n\
+            Forwarded: for=\"[::1]\";host=\"example.com:5000\";proto=http\r\n\
+            Connection: close\r\n\r\n",
+            String::from_utf8(head).unwrap()
+        );
+
+        let req: Request = b"PUT /upload HTTP/1.1\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Content-Length: 10\r\n\r\n"
+            .to_vec()
+            .into();
+        let head = String::from_utf8(forward_head(&req, "app", None)).unwrap();
+        assert!(head.contains("\r\nTransfer-Encoding: chunked\r\n"));
+        assert!(!head.contains("Content-Length"));
+
+        // Methods the server does not know are passed on unchanged
+        let req: Request = b"PROPFIND /dav/ HTTP/1.1\r\nDepth: 1\r\n\r\n"
+            .to_vec()
+            .into();
+        let head = String::from_utf8(forward_head(&req, "app", None)).unwrap();
+        assert!(head.starts_with("PROPFIND /dav/ HTTP/1.1\r\n"));
+
+        let config = ProxyConfig {
+            prefix: "/".into(),
+            pass: "unix:/run/app.sock".into(),
+            host: None,
+            connect_timeout: 10,
+            timeout: 60,
+            client_max_body_size: None,
+        };
+        assert_eq!("localhost", config.upstream_host());
+    }
+
+    #[tokio::test]
+    async fn test_read_response_head() {
+        let mut reader = BufReader::new(
+            &b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"[..],
+        );
+        let (status, headers) = read_response_head(&mut reader).await.unwrap().unwrap();
+        assert_eq!(HttpStatus::NotFound, status);
+        assert_eq!(
+            vec![("Content-Length".to_string(), "0".to_string())],
+            headers
+        );
+        let mut reader = BufReader::new(&b"SSH-2.0-OpenSSH\r\n"[..]);
+        assert!(read_response_head(&mut reader).await.unwrap().is_none());
+        for head in [
+            &b"HTTP/1.1 0 \r\n\r\n"[..],
+            b"HTTP/1.1 1000 Big\r\n\r\n",
+            b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n",
+        ] {
+            let mut reader = BufReader::new(head);
+            assert!(read_response_head(&mut reader).await.unwrap().is_none());
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use std::{fmt, io};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::http::{
+    chunked::ChunkedReader, request::Request, response::HttpStatus, uri::has_dot_segment,
+};
+
+// Largest request line plus header block accepted
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+#[derive(Debug)]
+pub enum ReadError {
+    // The connection failed or closed before a full request arrived
+    Io(io::Error),
+    HeadersTooLarge,
+    BodyTooLarge,
+    // The body uses a transfer coding other than `chunked`
+    LengthRequired,
+    BadRequest,
+    // A chunked body is malformed
+    BadChunk,
+    // The path has `.` or `..` segments
+    BadPath,
+}
+
+impl ReadError {
+    // Status to answer with, `None` when the client is gone
+    pub fn status(&self) -> Option<HttpStatus> {
+        match self {
+            ReadError::Io(_) => None,
+            ReadError::HeadersTooLarge => Some(HttpStatus::RequestHeaderFieldsTooLarge),
+            ReadError::BodyTooLarge => Some(HttpStatus::ContentTooLarge),
+            ReadError::LengthRequired => Some(HttpStatus::LengthRequired),
+            ReadError::BadRequest | ReadError::BadChunk | ReadError::BadPath => {
+                Some(HttpStatus::BadRequest)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "{e}"),
+            ReadError::HeadersTooLarge => write!(f, "header block too large"),
+            ReadError::BodyTooLarge => write!(f, "body too large"),
+            ReadError::LengthRequired => write!(f, "unsupported Transfer-Encoding"),
+            ReadError::BadRequest => write!(f, "invalid Content-Length"),
+            ReadError::BadChunk => write!(f, "malformed chunked body"),
+            ReadError::BadPath => write!(f, "dot segments in the path"),
+        }
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> Self {
+        ReadError::Io(e)
+    }
+}
+
+// Read the header block. Returns it with the bytes that came after it, the
+// start of the body.
+pub async fn read_request_head(stream: &mut TcpStream) -> Result<Vec<u8>, ReadError> {
+    let mut buffer = Vec::with_capacity(1024);
+    let mut block = [0; 4096];
+    let head_end = loop {
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Err(ReadError::HeadersTooLarge);
+        }
+        let read = stream.read(&mut block).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        buffer.extend_from_slice(&block[..read]);
+    };
+    if head_end > MAX_HEADER_SIZE {
+        return Err(ReadError::HeadersTooLarge);
+    }
+    let req: Request = buffer[..head_end].to_vec().into();
+    if has_dot_segment(req.uri_path()) {
+        return Err(ReadError::BadPath);
+    }
+    Ok(buffer)
+}
+
+// Read the rest of a body of at most `max_body_size` bytes into `buffer`.
+// Returns the raw head and body.
+pub async fn read_body(
+    stream: &mut TcpStream,
+    mut buffer: Vec<u8>,
+    max_body_size: u64,
+) -> Result<Vec<u8>, ReadError> {
+    let req: Request = buffer.clone().into();
+    let Some(length) = body_length(&req)? else {
+        return read_chunked_body(stream, &buffer, &req, max_body_size).await;
+    };
+    if length > max_body_size {
+        return Err(ReadError::BodyTooLarge);
+    }
+
+    let total = buffer.len() - req.body().len() + length as usize;
+    let mut block = [0; 4096];
+    while buffer.len() < total {
+        let read = stream.read(&mut block).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        buffer.extend_from_slice(&block[..read]);
+    }
+    // Pipelined bytes past the body are not supported and dropped
+    buffer.truncate(total);
+    Ok(buffer)
+}
+
+// Read a chunked body into memory. The head it is returned with carries its
+// length in place of `Transfer-Encoding`, as scripts expect.
+async fn read_chunked_body(
+    stream: &mut TcpStream,
+    buffer: &[u8],
+    req: &Request,
+    max_body_size: u64,
+) -> Result<Vec<u8>, ReadError> {
+    let head = &buffer[..buffer.len() - req.body().len()];
+    let mut body = RequestBody::new(req, stream, Some(max_body_size)).await?;
+    body.buffer(max_body_size).await?;
+
+    let mut request = Vec::new();
+    for line in head.split(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let name = line.split(|&b| b == b':').next().unwrap_or_default();
+        if line.is_empty()
+            || name.eq_ignore_ascii_case(b"Transfer-Encoding")
+            || name.eq_ignore_ascii_case(b"Content-Length")
+        {
+            continue;
+        }
+        request.extend_from_slice(line);
+        request.extend_from_slice(b"\r\n");
+    }
+    let length = body.length().unwrap_or(0);
+    request.extend_from_slice(format!("Content-Length: {}\r\n\r\n", length).as_bytes());
+    let mut block = [0; 8192];
+    loop {
+        let read = body.read(&mut block).await?;
+        if read == 0 {
+            return Ok(request);
+        }
+        request.extend_from_slice(&block[..read]);
+    }
+}
+
+// Length of the body of `req`, `None` when it is chunked
+pub fn body_length(req: &Request) -> Result<Option<u64>, ReadError> {
+    match req.header("Transfer-Encoding") {
+        None => {}
+        Some(coding) if coding.eq_ignore_ascii_case("identity") => {}
+        Some(coding) if coding.eq_ignore_ascii_case("chunked") => return Ok(None),
+        Some(_) => return Err(ReadError::LengthRequired),
+    }
+    match req.header("Content-Length") {
+        Some(length) => length.parse().map(Some).map_err(|_| ReadError::BadRequest),
+        None => Ok(Some(0)),
+    }
+}
+
+// The body of a request read with `read_request_head`, read as it arrives:
+// first the bytes that came with the head, then the rest from the connection
+pub struct RequestBody<'a> {
+    // `None` while a chunked body has not been buffered
+    length: Option<u64>,
+    reader: Box<dyn AsyncRead + Send + Unpin + 'a>,
+    // Largest body accepted
+    limit: Option<u64>,
+    read: u64,
+}
+
+impl<'a> RequestBody<'a> {
+    // Answers `Expect: 100-continue` so the client sends the rest of the body
+    pub async fn new(
+        req: &'a Request,
+        stream: &'a mut TcpStream,
+        limit: Option<u64>,
+    ) -> Result<Self, ReadError> {
+        let length = body_length(req)?;
+        if length
+            .zip(limit)
+            .is_some_and(|(length, limit)| length > limit)
+        {
+            return Err(ReadError::BodyTooLarge);
+        }
+        let expects = req
+            .header("Expect")
+            .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+        if expects && req.body().is_empty() && length != Some(0) {
+            stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await?;
+        }
+        let received = req.body().chain(stream);
+        let reader: Box<dyn AsyncRead + Send + Unpin> = match length {
+            Some(length) => Box::new(received.take(length)),
+            None => Box::new(ChunkedReader::new(BufReader::new(received))),
+        };
+        Ok(Self {
+            length,
+            reader,
+            limit,
+            read: 0,
+        })
+    }
+
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    // Read the next bytes of the body, `0` at its end
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        let read = self.reader.read(buf).await.map_err(|e| match e.kind() {
+            io::ErrorKind::InvalidData => ReadError::BadChunk,
+            _ => ReadError::Io(e),
+        })?;
+        if read == 0 && self.length.is_some_and(|length| self.read < length) {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.read += read as u64;
+        if self.limit.is_some_and(|limit| self.read > limit) {
+            return Err(ReadError::BodyTooLarge);
+        }
+        Ok(read)
+    }
+
+    // Read a chunked body of at most `max_size` bytes into memory, for
+    // protocols that send the length before the body
+    pub async fn buffer(&mut self, max_size: u64) -> Result<(), ReadError> {
+        if self.length.is_some() {
+            return Ok(());
+        }
+        self.limit = Some(self.limit.map_or(max_size, |limit| limit.min(max_size)));
+        let mut body = Vec::new();
+        let mut block = [0; 8192];
+        loop {
+            let read = self.read(&mut block).await?;
+            if read == 0 {
+                break;
+            }
+            body.extend_from_slice(&block[..read]);
+        }
+        self.length = Some(body.len() as u64);
+        self.read = 0;
+        self.reader = Box::new(io::Cursor::new(body));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use crate::http::request::Request;
+
+    use super::{read_body, read_request_head, ReadError, RequestBody};
+
+    // The server end of a connection the client sends `data` on
+    async fn connect(data: &'static [u8]) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            // Split the request so it arrives over several reads
+            for part in data.chunks(5) {
+                client.write_all(part).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            client.shutdown().await.unwrap();
+        });
+        server
+    }
+
+    async fn read(data: &'static [u8], max_body_size: u64) -> Result<Vec<u8>, ReadError> {
+        let mut server = connect(data).await;
+        let head = read_request_head(&mut server).await?;
+        read_body(&mut server, head, max_body_size).await
+    }
+
+    #[tokio::test]
+    async fn test_read_request() {
+        let get = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert_eq!(get.to_vec(), read(get, 0).await.unwrap());
+
+        let post = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(post.to_vec(), read(post, 5).await.unwrap());
+
+        let result = read(post, 4).await;
+        assert!(matches!(result, Err(ReadError::BodyTooLarge)));
+        // Chunked bodies are read whole and given a length
+        let chunked = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\nX-A: 1\r\n\r\n\
+            3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n";
+        assert_eq!(
+            b"POST /a HTTP/1.1\r\nX-A: 1\r\nContent-Length: 5\r\n\r\nhello".to_vec(),
+            read(chunked, 5).await.unwrap()
+        );
+        let result = read(chunked, 4).await;
+        assert!(matches!(result, Err(ReadError::BodyTooLarge)));
+        let result = read(b"POST /a HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n", 5).await;
+        assert!(matches!(result, Err(ReadError::LengthRequired)));
+        let result = read(b"GET /api/%2e%2e/admin HTTP/1.1\r\n\r\n", 0).await;
+        assert!(matches!(result, Err(ReadError::BadPath)));
+        let result = read(b"POST /a HTTP/1.1\r\nContent-Length: 9\r\n\r\nshort", 9).await;
+        assert!(matches!(result, Err(ReadError::Io(_))));
+    }
+
+    // The body of the request sent as `data`, read in small blocks
+    async fn stream_body(data: &'static [u8], limit: Option<u64>) -> Result<Vec<u8>, ReadError> {
+        let mut server = connect(data).await;
+        let req: Request = read_request_head(&mut server).await?.into();
+        let mut body = RequestBody::new(&req, &mut server, limit).await?;
+        let mut received = Vec::new();
+        let mut block = [0; 3];
+        loop {
+            let read = body.read(&mut block).await?;
+            if read == 0 {
+                return Ok(received);
+            }
+            received.extend_from_slice(&block[..read]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_body() {
+        let sized = b"PUT /a HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello worldGET /";
+        assert_eq!(
+            b"hello world".to_vec(),
+            stream_body(sized, None).await.unwrap()
+        );
+        let result = stream_body(sized, Some(10)).await;
+        assert!(matches!(result, Err(ReadError::BodyTooLarge)));
+
+        let chunked = b"PUT /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        assert_eq!(
+            b"hello world".to_vec(),
+            stream_body(chunked, None).await.unwrap()
+        );
+        let result = stream_body(chunked, Some(10)).await;
+        assert!(matches!(result, Err(ReadError::BodyTooLarge)));
+        let bad = b"PUT /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        let result = stream_body(bad, None).await;
+        assert!(matches!(result, Err(ReadError::BadChunk)));
+        // Buffering gives a chunked body its length
+        let mut server = connect(chunked).await;
+        let req: Request = read_request_head(&mut server).await.unwrap().into();
+        let mut body = RequestBody::new(&req, &mut server, None).await.unwrap();
+        assert_eq!(None, body.length());
+        body.buffer(1024).await.unwrap();
+        assert_eq!(Some(11), body.length());
+        let mut block = [0; 16];
+        assert_eq!(11, body.read(&mut block).await.unwrap());
+        assert_eq!(b"hello world", &block[..11]);
+    }
+}
@@ -0,0 +1,237 @@
+use std::{fs::File, io, ops::Range};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    net::TcpStream,
+};
+
+use crate::{
+    config::Config,
+    http::{
+        encoding::{negotiate, Encoder, Encoding},
+        request::{HttpMethod, Request},
+        response::{HttpStatus, Response},
+    },
+};
+
+use super::sendfile::copy_range;
+
+// Size of the blocks fed to the compressor between chunk writes
+const BLOCK_SIZE: usize = 16 * 1024;
+
+// Write `response`, compressing its body when the client accepts a coding
+// we offer and the content type is worth compressing
+pub async fn send(
+    stream: &mut TcpStream,
+    req: &Request,
+    config: &Config,
+    response: &mut Response<'_>,
+) -> io::Result<()> {
+    let body_len = response.body().len() as u64;
+    let encoder = start_encoding(req, config, response, body_len);
+    if req.method() == HttpMethod::Head {
+        return send_head(stream, response).await;
+    }
+    let mut encoder = match encoder {
+        Some(encoder) => encoder,
+        None => {
+            stream.write_all(&response.as_bytes()).await?;
+            return stream.flush().await;
+        }
+    };
+
+    stream.write_all(&response.head_bytes()).await?;
+    for block in response.body().chunks(BLOCK_SIZE) {
+        encoder.write(block)?;
+        write_chunk(stream, &encoder.take_output()).await?;
+    }
+    write_chunk(stream, &encoder.finish()?).await?;
+    stream.write_all(b"0\r\n\r\n").await?;
+    stream.flush().await
+}
+
+// Write `response` with `range` of `file` as its body. Uncompressed bodies
+// are copied by the kernel; compressed ones are read block by block.
+pub async fn send_stream(
+    stream: &mut TcpStream,
+    req: &Request,
+    config: &Config,
+    response: &mut Response<'_>,
+    file: &File,
+    range: Range<u64>,
+) -> io::Result<()> {
+    let encoder = start_encoding(req, config, response, range.end - range.start);
+    if req.method() == HttpMethod::Head {
+        return send_head(stream, response).await;
+    }
+    let mut encoder = match encoder {
+        Some(encoder) => encoder,
+        None => {
+            stream.write_all(&response.head_bytes()).await?;
+            copy_range(stream, file, range, config.sendfile).await?;
+            return stream.flush().await;
+        }
+    };
+
+    stream.write_all(&response.head_bytes()).await?;
+    let mut file = tokio::fs::File::from_std(file.try_clone()?);
+    file.seek(SeekFrom::Start(range.start)).await?;
+    let mut remaining = range.end - range.start;
+    let mut block = vec![0; BLOCK_SIZE];
+    while remaining > 0 {
+        let size = remaining.min(BLOCK_SIZE as u64) as usize;
+        let read = file.read(&mut block[..size]).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        encoder.write(&block[..read])?;
+        write_chunk(stream, &encoder.take_output()).await?;
+        remaining -= read as u64;
+    }
+    write_chunk(stream, &encoder.finish()?).await?;
+    stream.write_all(b"0\r\n\r\n").await?;
+    stream.flush().await
+}
+
+// Write `response` with a body read from `body` until it ends. Without a
+// `Content-Length` the body is sent chunked.
+pub async fn send_reader(
+    stream: &mut TcpStream,
+    req: &Request,
+    config: &Config,
+    response: &mut Response<'_>,
+    mut body: impl AsyncRead + Unpin,
+) -> io::Result<()> {
+    if !response.status().allows_body() {
+        response.remove_header("Content-Length");
+        stream.write_all(&response.head_bytes()).await?;
+        return stream.flush().await;
+    }
+    let length = response
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok());
+    let mut encoder = start_encoding(req, config, response, length.unwrap_or(u64::MAX));
+    let mut block = vec![0; BLOCK_SIZE];
+
+    if encoder.is_none() {
+        if let Some(length) = length {
+            if req.method() == HttpMethod::Head {
+                return send_head(stream, response).await;
+            }
+            stream.write_all(&response.head_bytes()).await?;
+            let copied = tokio::io::copy(&mut body.take(length), stream).await?;
+            if copied < length {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            return stream.flush().await;
+        }
+        response.set_headers("Transfer-Encoding".into(), "chunked".into());
+    }
+    if req.method() == HttpMethod::Head {
+        return send_head(stream, response).await;
+    }
+
+    stream.write_all(&response.head_bytes()).await?;
+    loop {
+        let read = body.read(&mut block).await?;
+        if read == 0 {
+            break;
+        }
+        match encoder.as_mut() {
+            Some(encoder) => {
+                encoder.write(&block[..read])?;
+                write_chunk(stream, &encoder.take_output()).await?;
+            }
+            None => write_chunk(stream, &block[..read]).await?,
+        }
+    }
+    if let Some(encoder) = encoder {
+        write_chunk(stream, &encoder.finish()?).await?;
+    }
+    stream.write_all(b"0\r\n\r\n").await?;
+    stream.flush().await
+}
+
+// Write a `multipart/byteranges` response whose parts, given as their
+// headers and ranges, are copied from `file`
+pub async fn send_multipart(
+    stream: &mut TcpStream,
+    req: &Request,
+    config: &Config,
+    response: &Response<'_>,
+    file: &File,
+    parts: &[(String, Range<u64>)],
+    trailer: &str,
+) -> io::Result<()> {
+    if req.method() == HttpMethod::Head {
+        return send_head(stream, response).await;
+    }
+    stream.write_all(&response.head_bytes()).await?;
+    for (header, range) in parts {
+        stream.write_all(header.as_bytes()).await?;
+        copy_range(stream, file, range.clone(), config.sendfile).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+    stream.write_all(trailer.as_bytes()).await?;
+    stream.flush().await
+}
+
+// Answer HEAD with the head a GET gets, its length headers included
+pub async fn send_head(stream: &mut TcpStream, response: &Response<'_>) -> io::Result<()> {
+    stream.write_all(&response.head_bytes()).await?;
+    stream.flush().await
+}
+
+// Pick the coding for a body of `body_len` bytes. When it gets compressed
+// the headers are updated for a chunked, encoded body and the encoder returned.
+fn start_encoding(
+    req: &Request,
+    config: &Config,
+    response: &mut Response<'_>,
+    body_len: u64,
+) -> Option<Encoder> {
+    let compression = &config.compression;
+    let compressible = compression.enabled
+        && response.status() == HttpStatus::Ok
+        && response.header("Content-Encoding").is_none()
+        && response
+            .header("Content-Type")
+            .is_some_and(|content_type| compression.compressible(content_type));
+    if !compressible {
+        return None;
+    }
+
+    response.add_vary("Accept-Encoding");
+    let encoding = if body_len >= compression.min_length as u64 {
+        negotiate(req.header("Accept-Encoding"), &compression.encodings)
+    } else {
+        Encoding::Identity
+    };
+    let encoder = Encoder::new(encoding, compression.level)?;
+
+    // The compressed bytes differ from the file, so a strong tag no longer holds
+    if let Some(etag) = response.header("ETag") {
+        if !etag.starts_with("W/") {
+            let weak = format!("W/{etag}");
+            response.set_headers("ETag".into(), weak);
+        }
+    }
+    response
+        .remove_header("Content-Length")
+        .set_headers("Content-Encoding".into(), encoding.to_string())
+        .set_headers("Transfer-Encoding".into(), "chunked".into());
+    Some(encoder)
+}
+
+// Write one chunk of a `Transfer-Encoding: chunked` body.
+// Empty data is skipped, since a zero-size chunk ends the body.
+pub async fn write_chunk(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    stream
+        .write_all(format!("{:x}\r\n", data.len()).as_bytes())
+        .await?;
+    stream.write_all(data).await?;
+    stream.write_all(b"\r\n").await
+}
@@ -0,0 +1,117 @@
+use std::{fs::File, io, ops::Range};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, SeekFrom},
+    net::TcpStream,
+};
+
+// Largest count a single sendfile(2) call transfers on Linux
+#[cfg(target_os = "linux")]
+const MAX_SENDFILE: u64 = 0x7fff_f000;
+
+// Copy `range` of `file` to the socket. On Linux the bytes go straight from
+// the page cache with sendfile(2) unless `zero_copy` is off; elsewhere, or
+// when the file system does not support it, they are copied through a buffer.
+pub async fn copy_range(
+    stream: &mut TcpStream,
+    file: &File,
+    range: Range<u64>,
+    zero_copy: bool,
+) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if zero_copy {
+        return sendfile(stream, file, range).await;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = zero_copy;
+    copy_buffered(stream, file, range).await
+}
+
+#[cfg(target_os = "linux")]
+async fn sendfile(stream: &mut TcpStream, file: &File, range: Range<u64>) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    use tokio::io::Interest;
+
+    let (socket, fd) = (stream.as_raw_fd(), file.as_raw_fd());
+    let mut offset = range.start as libc::off_t;
+    while (offset as u64) < range.end {
+        let count = (range.end - offset as u64).min(MAX_SENDFILE) as usize;
+        stream.writable().await?;
+        let sent = stream.try_io(Interest::WRITABLE, || {
+            // The kernel advances `offset`, the file position is left alone
+            match unsafe { libc::sendfile(socket, fd, &mut offset, count) } {
+                -1 => Err(io::Error::last_os_error()),
+                sent => Ok(sent as usize),
+            }
+        });
+        match sent {
+            // The file shrank after its length was sent
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) => {
+                return copy_buffered(stream, file, offset as u64..range.end).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+async fn copy_buffered(stream: &mut TcpStream, file: &File, range: Range<u64>) -> io::Result<()> {
+    let mut file = tokio::fs::File::from_std(file.try_clone()?);
+    file.seek(SeekFrom::Start(range.start)).await?;
+    let copied = tokio::io::copy(&mut file.take(range.end - range.start), stream).await?;
+    if copied < range.end - range.start {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::copy_range;
+
+    #[tokio::test]
+    async fn test_copy_range() {
+        let path = std::env::temp_dir().join(format!("minginx-sendfile-{}", std::process::id()));
+        let content: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(&content)
+            .unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            client.read_to_end(&mut received).await.unwrap();
+            received
+        });
+
+        copy_range(&mut server, &file, 10..70_000, true)
+            .await
+            .unwrap();
+        copy_range(&mut server, &file, 99_000..100_000, false)
+            .await
+            .unwrap();
+        drop(server);
+
+        let mut expected = content[10..70_000].to_vec();
+        expected.extend_from_slice(&content[99_000..]);
+        assert_eq!(expected, reader.await.unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
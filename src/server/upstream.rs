@@ -0,0 +1,154 @@
+use std::{future::Future, io, path::PathBuf, time::Duration};
+
+use tokio::{
+    io::{split, AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
+    time::{sleep_until, Instant},
+};
+
+use super::reader::{ReadError, RequestBody};
+
+// A connection to an application server
+pub trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+// Where an application server listens: `host:port` or `unix:/path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    pub fn parse(address: &str) -> Self {
+        match address.strip_prefix("unix:") {
+            Some(path) => Address::Unix(PathBuf::from(path)),
+            None => Address::Tcp(address.to_string()),
+        }
+    }
+
+    // `Host` to send when nothing else is configured
+    pub fn host(&self) -> &str {
+        match self {
+            Address::Tcp(address) => address,
+            Address::Unix(_) => "localhost",
+        }
+    }
+
+    pub async fn connect(&self) -> io::Result<Box<dyn Io>> {
+        match self {
+            Address::Tcp(address) => {
+                let stream = TcpStream::connect(address).await?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+}
+
+// Connect to `address` and send a request, returning the connection to read
+// the response from. The body is written in the background.
+pub async fn send_request(
+    address: &Address,
+    head: &[u8],
+    body: Vec<u8>,
+) -> io::Result<ReadHalf<Box<dyn Io>>> {
+    let (reader, mut writer) = split(address.connect().await?);
+    writer.write_all(head).await?;
+    tokio::spawn(async move {
+        let written = async {
+            writer.write_all(&body).await?;
+            writer.flush().await
+        };
+        if let Err(e) = written.await {
+            log::error!("Failed to send request body upstream: {}", e);
+        }
+        // Keep the connection open for the response
+        writer
+    });
+    Ok(reader)
+}
+
+// Connect to `address` and send a request head, returning both directions of
+// the connection: one to send the body, the other to read the response
+pub async fn open(
+    address: &Address,
+    head: &[u8],
+) -> io::Result<(ReadHalf<Box<dyn Io>>, WriteHalf<Box<dyn Io>>)> {
+    let (reader, mut writer) = split(address.connect().await?);
+    writer.write_all(head).await?;
+    Ok((reader, writer))
+}
+
+// Why a request body could not be sent on
+#[derive(Debug)]
+pub enum BodyError {
+    Client(ReadError),
+    Upstream(io::Error),
+}
+
+// Copy the body of a request upstream as it arrives. A body of unknown
+// length is sent chunked.
+pub async fn send_body(
+    body: &mut RequestBody<'_>,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), BodyError> {
+    let chunked = body.length().is_none();
+    let mut block = vec![0; 16 * 1024];
+    loop {
+        let read = body.read(&mut block).await.map_err(BodyError::Client)?;
+        let written = async {
+            if chunked {
+                writer.write_all(format!("{read:x}\r\n").as_bytes()).await?;
+                writer.write_all(&block[..read]).await?;
+                writer.write_all(b"\r\n").await?;
+            } else {
+                writer.write_all(&block[..read]).await?;
+            }
+            if read == 0 {
+                writer.flush().await?;
+            }
+            Ok(())
+        };
+        written.await.map_err(BodyError::Upstream)?;
+        if read == 0 {
+            return Ok(());
+        }
+    }
+}
+
+// Wait for the `response` while `sending` the request body. A response that
+// comes first ends the body early. Once the body is sent the response has
+// `timeout` to come, `Ok(None)` when it does not. Fails when the client's
+// body does.
+pub async fn exchange<T>(
+    sending: impl Future<Output = Result<(), BodyError>>,
+    response: impl Future<Output = T>,
+    timeout: Duration,
+) -> Result<Option<T>, ReadError> {
+    tokio::pin!(sending, response);
+    let mut sent = None;
+    loop {
+        tokio::select! {
+            result = &mut sending, if sent.is_none() => {
+                match result {
+                    Err(BodyError::Client(e)) => return Err(e),
+                    // The response may still explain why
+                    Err(BodyError::Upstream(e)) => {
+                        log::error!("Failed to send request body upstream: {}", e)
+                    }
+                    Ok(()) => {}
+                }
+                sent = Some(Instant::now() + timeout);
+            }
+            head = &mut response => return Ok(Some(head)),
+            _ = sleep_until(sent.unwrap_or_else(Instant::now)), if sent.is_some() => {
+                return Ok(None);
+            }
+        }
+    }
+}